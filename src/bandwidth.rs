@@ -0,0 +1,177 @@
+use super::error::RssDumpError;
+use super::utils::{parse_size, url_host};
+
+use tokio::time::{sleep, Instant};
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Caps on the bytes per second downloads may use: one for all downloads together and one for
+/// the downloads from each of some hosts.
+///
+/// Clones share their caps, so every feed configured with a clone of the same limit shares the
+/// same budget.
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLimit {
+    global: Option<Arc<TokenBucket>>,
+    host_rates: BTreeMap<String, u64>,
+    hosts: Arc<Mutex<HashMap<String, Arc<TokenBucket>>>>,
+}
+
+impl BandwidthLimit {
+    /// Download at most `rate` bytes per second, adding up every download.
+    pub fn with_rate(mut self, rate: Option<u64>) -> Self {
+        self.global = rate.map(|rate| Arc::new(TokenBucket::new(rate)));
+        self
+    }
+
+    /// Download at most `rate` bytes per second from `host`, adding up every download from it.
+    pub fn with_host_rate(mut self, host: &str, rate: u64) -> Self {
+        self.host_rates.insert(host.to_lowercase(), rate);
+        self
+    }
+
+    /// Throttle for a download from `url`, sharing its budget with every other download
+    /// counting against the same caps.
+    pub fn throttle(&self, url: &str) -> Throttle {
+        let mut buckets: Vec<Arc<TokenBucket>> = self.global.iter().cloned().collect();
+
+        if let Some((host, rate)) =
+            url_host(url).and_then(|host| self.host_rates.get(&host).map(|rate| (host, *rate)))
+        {
+            let mut hosts = self.hosts.lock().unwrap();
+            let bucket = hosts
+                .entry(host)
+                .or_insert_with(|| Arc::new(TokenBucket::new(rate)));
+            buckets.push(Arc::clone(bucket));
+        }
+
+        Throttle { buckets }
+    }
+}
+
+/// The caps a single download counts against.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl Throttle {
+    /// Account for `bytes` just downloaded, waiting as long as it takes to stay under every cap.
+    pub async fn consume(&self, bytes: usize) {
+        let wait = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.take(bytes as f64))
+            .max()
+            .unwrap_or_default();
+
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+/// Bytes that can be downloaded right away, refilled at `rate` bytes per second up to one
+/// second worth of them. Taking more than there is leaves the bucket in debt, which whoever
+/// takes it waits out, so concurrent downloads share the rate instead of racing for it.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1) as f64,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` out of the bucket, returning how long to wait until they are paid for.
+    fn take(&self, bytes: f64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (available, last_refill) = &mut *state;
+
+        let now = Instant::now();
+        *available =
+            (*available + (now - *last_refill).as_secs_f64() * self.rate).min(self.rate) - bytes;
+        *last_refill = now;
+
+        if *available < 0.0 {
+            Duration::from_secs_f64(-*available / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Parse a rate in bytes per second, like "500K", "2M" or "1.5G". Suffixes are powers of 1024.
+pub fn parse_rate(rate: &str) -> Result<u64, Box<RssDumpError>> {
+    parse_size(rate)
+        .filter(|rate| *rate > 0)
+        .ok_or_else(|| Box::new(RssDumpError::InvalidRate(rate.to_owned())))
+}
+
+/// Parse a per-host cap given as "HOST=RATE".
+pub fn parse_host_rate(host_rate: &str) -> Result<(String, u64), Box<RssDumpError>> {
+    match host_rate.split_once('=') {
+        Some((host, rate)) if !host.trim().is_empty() => {
+            Ok((host.trim().to_owned(), parse_rate(rate)?))
+        }
+        _ => Err(Box::new(RssDumpError::InvalidRate(host_rate.to_owned()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!(parse_rate("2M").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_rate("500k").unwrap(), 500 * 1024);
+        assert_eq!(parse_rate("1.5G").unwrap(), 3 * 512 * 1024 * 1024);
+        assert_eq!(parse_rate(" 4096 ").unwrap(), 4096);
+        assert!(parse_rate("2X").is_err());
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+
+        assert_eq!(
+            parse_host_rate("cdn.example.com=1M").unwrap(),
+            (String::from("cdn.example.com"), 1024 * 1024)
+        );
+        assert_eq!(
+            parse_host_rate(" cdn.example.com = 512K").unwrap(),
+            (String::from("cdn.example.com"), 512 * 1024)
+        );
+        assert!(parse_host_rate("=1M").is_err());
+        assert!(parse_host_rate(" =1M").is_err());
+        assert!(parse_host_rate("cdn.example.com").is_err());
+        assert!(parse_host_rate("cdn.example.com=").is_err());
+        assert!(parse_host_rate("cdn.example.com=fast").is_err());
+    }
+
+    #[test]
+    fn throttles_share_host_buckets() {
+        let limit = BandwidthLimit::default()
+            .with_rate(Some(1000))
+            .with_host_rate("CDN.example.com", 100);
+
+        assert_eq!(
+            limit.throttle("http://cdn.example.com/a.mp3").buckets.len(),
+            2
+        );
+        assert_eq!(limit.throttle("http://example.com/a.mp3").buckets.len(), 1);
+
+        // Both downloads from the host drain the same bucket
+        let (first, second) = (
+            limit.throttle("http://cdn.example.com/a.mp3"),
+            limit.clone().throttle("http://cdn.example.com/b.mp3"),
+        );
+        assert_eq!(first.buckets[1].take(100.0), Duration::ZERO);
+        assert!(second.buckets[1].take(50.0) > Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,172 @@
+use super::error::RssDumpError;
+use super::utils::cache_dir;
+
+use chrono::{DateTime, Utc};
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::fs;
+use std::path::PathBuf;
+
+/// Validators sent back to the server to find out whether a cached feed changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheValidators {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// When the body was fetched and stored.
+    #[serde(default)]
+    pub stored_at: Option<DateTime<Utc>>,
+}
+
+/// On disk cache of fetched feeds, keyed by url and request headers, since headers like
+/// `Authorization` may change the feed the server sends. Each feed is stored as its raw body
+/// next to a JSON file with the validators the server sent along with it.
+#[derive(Debug)]
+pub struct FeedCache {
+    dir: PathBuf,
+}
+
+impl Default for FeedCache {
+    fn default() -> Self {
+        Self::new(cache_dir().join("feeds"))
+    }
+}
+
+impl FeedCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn paths(&self, url: &str, headers: &HeaderMap) -> (PathBuf, PathBuf) {
+        let mut headers: Vec<_> = headers.iter().collect();
+        headers.sort_by(|(name, value), (other_name, other_value)| {
+            (name.as_str(), value.as_bytes()).cmp(&(other_name.as_str(), other_value.as_bytes()))
+        });
+
+        let mut hasher = Sha256::new();
+        hasher.update(url.as_bytes());
+        for (name, value) in headers {
+            hasher.update(b"\n");
+            hasher.update(name.as_str().as_bytes());
+            hasher.update(b": ");
+            hasher.update(value.as_bytes());
+        }
+        let key = format!("{:x}", hasher.finalize());
+        (
+            self.dir.join(format!("{}.json", key)),
+            self.dir.join(format!("{}.feed", key)),
+        )
+    }
+
+    /// Cached validators and body of `url`, requested with `headers`. Unreadable cache entries
+    /// count as missing.
+    pub fn load(&self, url: &str, headers: &HeaderMap) -> Option<(CacheValidators, Vec<u8>)> {
+        let (validators_path, body_path) = self.paths(url, headers);
+
+        let validators: CacheValidators =
+            serde_json::from_slice(&fs::read(validators_path).ok()?).ok()?;
+        let body = fs::read(body_path).ok()?;
+
+        // Guard against hash collisions, however unlikely
+        if validators.url == url {
+            Some((validators, body))
+        } else {
+            None
+        }
+    }
+
+    pub fn store(
+        &self,
+        validators: &CacheValidators,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<(), Box<RssDumpError>> {
+        let (validators_path, body_path) = self.paths(&validators.url, headers);
+
+        fs::create_dir_all(&self.dir)?;
+        // Without validators the body is never used, so a half written body is harmless
+        if validators_path.exists() {
+            fs::remove_file(&validators_path)?;
+        }
+        fs::write(body_path, body)?;
+        fs::write(validators_path, serde_json::to_vec_pretty(validators)?)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn store_and_load() {
+        let dir = TempDir::new("cache");
+        let cache = FeedCache::new(dir.path().to_path_buf());
+        let url = "http://example.com/feed.xml";
+        let headers = HeaderMap::new();
+        assert_eq!(cache.load(url, &headers), None);
+
+        let validators = CacheValidators {
+            url: url.to_owned(),
+            etag: Some(String::from("W/\"cheese\"")),
+            last_modified: Some(String::from("Sat, 01 Jan 2022 00:00:00 GMT")),
+            stored_at: Some(Utc::now()),
+        };
+        cache.store(&validators, &headers, b"<rss/>").unwrap();
+        assert_eq!(
+            cache.load(url, &headers),
+            Some((validators, b"<rss/>".to_vec()))
+        );
+        assert_eq!(cache.load("http://example.com/other.xml", &headers), None);
+
+        // Storing again replaces the entry
+        let validators = CacheValidators {
+            url: url.to_owned(),
+            etag: None,
+            last_modified: Some(String::from("Sun, 02 Jan 2022 00:00:00 GMT")),
+            stored_at: Some(Utc::now()),
+        };
+        cache.store(&validators, &headers, b"<rss></rss>").unwrap();
+        assert_eq!(
+            cache.load(url, &headers),
+            Some((validators, b"<rss></rss>".to_vec()))
+        );
+    }
+
+    #[test]
+    fn key_by_headers() {
+        let dir = TempDir::new("cache-headers");
+        let cache = FeedCache::new(dir.path().to_path_buf());
+        let url = "http://example.com/feed.xml";
+        let header_map = |headers: &[(&'static str, &'static str)]| {
+            let mut header_map = HeaderMap::new();
+            for (name, value) in headers {
+                header_map.insert(*name, value.parse().unwrap());
+            }
+            header_map
+        };
+        let alice = header_map(&[("authorization", "Bearer alice"), ("accept", "*/*")]);
+        let bob = header_map(&[("authorization", "Bearer bob")]);
+        let validators = CacheValidators {
+            url: url.to_owned(),
+            etag: Some(String::from("\"alice\"")),
+            ..CacheValidators::default()
+        };
+
+        // Feeds requested with other headers are cached apart
+        cache.store(&validators, &alice, b"<rss/>").unwrap();
+        assert_eq!(cache.load(url, &bob), None);
+        assert_eq!(cache.load(url, &HeaderMap::new()), None);
+
+        // Whatever the order of the headers
+        let alice = header_map(&[("accept", "*/*"), ("authorization", "Bearer alice")]);
+        assert_eq!(
+            cache.load(url, &alice),
+            Some((validators, b"<rss/>".to_vec()))
+        );
+    }
+}
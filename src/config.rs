@@ -1,22 +1,261 @@
+use super::bandwidth::{parse_rate, BandwidthLimit};
 use super::error::RssDumpError;
+use super::retry::RetryPolicy;
+use super::sanitize::SanitizePolicy;
+use super::space::{SpaceCheck, SpacePolicy};
+use super::template::FilenameTemplate;
 use super::utils::*;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::fs;
 use std::path::{self, Path, PathBuf};
 
+/// Name of the file, inside the configuration directory, holding the defaults and profiles.
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Settings that can be given in the configuration file. Every setting is optional, missing
+/// ones are taken from the defaults and then from the command line defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub url: Option<String>,
+    pub file: Option<String>,
+    pub output: Option<String>,
+    pub query: Option<String>,
+    pub ndownloads: Option<usize>,
+    pub timeout: Option<usize>,
+    pub filename_template: Option<String>,
+    /// Filesystems downloaded file names must be valid in: "portable", "windows" or "posix".
+    pub sanitize: Option<String>,
+    /// Attempts made for every request before giving up.
+    pub retries: Option<u32>,
+    /// Delay before the first retry, e.g. "1s", doubled on every further retry.
+    pub retry_delay: Option<String>,
+    /// Longest delay between retries, e.g. "1m".
+    pub retry_max_delay: Option<String>,
+    /// How often `watch` polls the feed, e.g. "30m" or "1h 30m".
+    pub interval: Option<String>,
+    /// Maximum number of concurrent downloads from the same host.
+    pub host_downloads: Option<usize>,
+    /// Byte ranges of a single download fetched at once.
+    pub segments: Option<usize>,
+    /// What to do when downloads don't fit in the free space: "fail", "trim" or "ignore".
+    pub space_policy: Option<String>,
+    /// Bytes kept free after downloading, e.g. "1G".
+    pub space_reserve: Option<String>,
+    /// Bytes per second all downloads may use together, e.g. "2M".
+    pub limit_rate: Option<String>,
+    /// Bytes per second the downloads from each host may use together.
+    #[serde(default)]
+    pub host_limit_rates: BTreeMap<String, String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Profile {
+    /// Fill every setting missing from this profile with the one in `defaults`.
+    fn or(self, defaults: &Profile) -> Self {
+        let mut headers = defaults.headers.clone();
+        headers.extend(self.headers);
+        let mut host_limit_rates = defaults.host_limit_rates.clone();
+        host_limit_rates.extend(self.host_limit_rates);
+
+        Self {
+            url: self.url.or_else(|| defaults.url.clone()),
+            file: self.file.or_else(|| defaults.file.clone()),
+            output: self.output.or_else(|| defaults.output.clone()),
+            query: self.query.or_else(|| defaults.query.clone()),
+            ndownloads: self.ndownloads.or(defaults.ndownloads),
+            timeout: self.timeout.or(defaults.timeout),
+            filename_template: self
+                .filename_template
+                .or_else(|| defaults.filename_template.clone()),
+            sanitize: self.sanitize.or_else(|| defaults.sanitize.clone()),
+            retries: self.retries.or(defaults.retries),
+            retry_delay: self.retry_delay.or_else(|| defaults.retry_delay.clone()),
+            retry_max_delay: self
+                .retry_max_delay
+                .or_else(|| defaults.retry_max_delay.clone()),
+            interval: self.interval.or_else(|| defaults.interval.clone()),
+            host_downloads: self.host_downloads.or(defaults.host_downloads),
+            segments: self.segments.or(defaults.segments),
+            space_policy: self.space_policy.or_else(|| defaults.space_policy.clone()),
+            space_reserve: self
+                .space_reserve
+                .or_else(|| defaults.space_reserve.clone()),
+            limit_rate: self.limit_rate.or_else(|| defaults.limit_rate.clone()),
+            host_limit_rates,
+            headers,
+        }
+    }
+
+    pub fn parse_filename_template(&self) -> Result<Option<FilenameTemplate>, Box<RssDumpError>> {
+        self.filename_template
+            .as_deref()
+            .map(FilenameTemplate::parse)
+            .transpose()
+    }
+
+    pub fn parse_sanitize_policy(&self) -> Result<SanitizePolicy, Box<RssDumpError>> {
+        self.sanitize
+            .as_deref()
+            .map_or_else(|| Ok(SanitizePolicy::default()), str::parse)
+    }
+
+    pub fn parse_retry_policy(&self) -> Result<RetryPolicy, Box<RssDumpError>> {
+        let mut retry_policy = RetryPolicy::default();
+
+        if let Some(retries) = self.retries {
+            retry_policy = retry_policy.with_max_attempts(retries);
+        }
+        if let Some(retry_delay) = &self.retry_delay {
+            retry_policy = retry_policy.with_base_delay(humantime::parse_duration(retry_delay)?);
+        }
+        if let Some(retry_max_delay) = &self.retry_max_delay {
+            retry_policy = retry_policy.with_max_delay(humantime::parse_duration(retry_max_delay)?);
+        }
+
+        Ok(retry_policy)
+    }
+
+    pub fn parse_space_check(&self) -> Result<SpaceCheck, Box<RssDumpError>> {
+        let mut space_check = SpaceCheck::default();
+
+        if let Some(space_policy) = &self.space_policy {
+            space_check = space_check.with_policy(space_policy.parse::<SpacePolicy>()?);
+        }
+        if let Some(space_reserve) = &self.space_reserve {
+            space_check = space_check.with_reserve(
+                parse_size(space_reserve)
+                    .ok_or_else(|| Box::new(RssDumpError::InvalidSize(space_reserve.clone())))?,
+            );
+        }
+
+        Ok(space_check)
+    }
+
+    /// Bandwidth caps of this profile. Every call creates new caps, so share clones of the
+    /// result between the feeds that should share a budget.
+    pub fn parse_bandwidth_limit(&self) -> Result<BandwidthLimit, Box<RssDumpError>> {
+        let mut bandwidth_limit = BandwidthLimit::default()
+            .with_rate(self.limit_rate.as_deref().map(parse_rate).transpose()?);
+
+        for (host, rate) in &self.host_limit_rates {
+            bandwidth_limit = bandwidth_limit.with_host_rate(host, parse_rate(rate)?);
+        }
+
+        Ok(bandwidth_limit)
+    }
+
+    /// Headers sent with every request made for this profile.
+    pub fn header_map(&self) -> Result<HeaderMap, Box<RssDumpError>> {
+        let mut header_map = HeaderMap::new();
+
+        for (name, value) in &self.headers {
+            let invalid_header = || Box::new(RssDumpError::InvalidHeader(name.clone()));
+
+            header_map.insert(
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid_header())?,
+                HeaderValue::from_str(value).map_err(|_| invalid_header())?,
+            );
+        }
+
+        Ok(header_map)
+    }
+}
+
+/// Contents of the configuration file: the defaults for every run and a set of named profiles.
+///
+/// ```toml
+/// [defaults]
+/// ndownloads = 4
+///
+/// [profiles.cheese]
+/// url = "https://example.com/feed.xml"
+/// output = "~/Podcasts/Cheese"
+/// query = "new"
+/// headers = { Authorization = "Bearer token" }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    defaults: Profile,
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+}
+
+impl ConfigFile {
+    /// Default location of the configuration file.
+    pub fn default_path() -> PathBuf {
+        config_dir().join(CONFIG_FILE_NAME)
+    }
+
+    /// Load the configuration file in `path`. A missing file yields an empty configuration.
+    pub fn load(path: &Path) -> Result<Self, Box<RssDumpError>> {
+        if path.is_file() {
+            info!("Loading configuration from {}", path.display());
+            Self::read_from(&fs::read_to_string(path)?)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    pub fn read_from(content: &str) -> Result<Self, Box<RssDumpError>> {
+        let mut config: Self = toml::from_str(content)?;
+
+        for profile in std::iter::once(&mut config.defaults).chain(config.profiles.values_mut()) {
+            profile.output = profile.output.as_deref().map(expand_home);
+            profile.file = profile.file.as_deref().map(expand_home);
+        }
+
+        Ok(config)
+    }
+
+    /// Settings of the profile called `name` on top of the defaults, or just the defaults
+    /// when no profile is given.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile, Box<RssDumpError>> {
+        match name {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .map(|profile| profile.clone().or(&self.defaults))
+                .ok_or_else(|| Box::new(RssDumpError::UnknownProfile(name.to_owned()))),
+            None => Ok(self.defaults.clone()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct DumpConfig<'input_life> {
     pub(super) output: PathBuf,
     pub(super) n_downloads: usize,
     pub(super) timeout: usize,
+    host_downloads: Option<usize>,
+    segments: usize,
     feed: &'input_life str,
     output_is_file: bool,
+    headers: HeaderMap,
+    client: reqwest::Client,
+    filename_template: Option<FilenameTemplate>,
+    sanitize_policy: SanitizePolicy,
+    retry_policy: RetryPolicy,
+    bandwidth_limit: BandwidthLimit,
+    space_check: SpaceCheck,
 }
 
 impl<'input_life> DumpConfig<'input_life> {
+    /// Configuration to download into the directory `output_path`. Every request is made with
+    /// `client`, sharing its connections with every other configuration holding a clone of it.
     pub fn new_output_is_dir(
         output_path: &str,
         n_downloads: usize,
         feed: &'input_life str,
         timeout: usize,
+        client: reqwest::Client,
     ) -> Self {
         let output = PathBuf::from(output_path);
         DumpConfig {
@@ -24,15 +263,26 @@ impl<'input_life> DumpConfig<'input_life> {
             n_downloads,
             feed,
             timeout,
+            host_downloads: None,
+            segments: 1,
             output_is_file: false,
+            headers: HeaderMap::new(),
+            client,
+            filename_template: None,
+            sanitize_policy: SanitizePolicy::default(),
+            retry_policy: RetryPolicy::default(),
+            bandwidth_limit: BandwidthLimit::default(),
+            space_check: SpaceCheck::default(),
         }
     }
 
+    /// Configuration to write into the file `output_path`, making every request with `client`.
     pub fn new_output_is_file(
         output_path: &str,
         n_downloads: usize,
         feed: &'input_life str,
         timeout: usize,
+        client: reqwest::Client,
     ) -> Result<Self, Box<RssDumpError>> {
         let output = PathBuf::from(output_path);
 
@@ -44,11 +294,70 @@ impl<'input_life> DumpConfig<'input_life> {
                 n_downloads,
                 feed,
                 timeout,
+                host_downloads: None,
+                segments: 1,
                 output_is_file: true,
+                headers: HeaderMap::new(),
+                client,
+                filename_template: None,
+                sanitize_policy: SanitizePolicy::default(),
+                retry_policy: RetryPolicy::default(),
+                bandwidth_limit: BandwidthLimit::default(),
+                space_check: SpaceCheck::default(),
             })
         }
     }
 
+    /// Send `headers` with every request made for this feed.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Download at most `host_downloads` items from the same host at once.
+    pub fn with_host_downloads(mut self, host_downloads: Option<usize>) -> Self {
+        self.host_downloads = host_downloads;
+        self
+    }
+
+    /// Fetch up to `segments` byte ranges of every download at once, from servers that
+    /// serve them.
+    pub fn with_segments(mut self, segments: usize) -> Self {
+        self.segments = segments.max(1);
+        self
+    }
+
+    /// Name downloads after `filename_template` instead of their title.
+    pub fn with_filename_template(mut self, filename_template: Option<FilenameTemplate>) -> Self {
+        self.filename_template = filename_template;
+        self
+    }
+
+    /// Make the names of downloads valid in the filesystems of `sanitize_policy`.
+    pub fn with_sanitize_policy(mut self, sanitize_policy: SanitizePolicy) -> Self {
+        self.sanitize_policy = sanitize_policy;
+        self
+    }
+
+    /// Retry failed requests according to `retry_policy`.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Keep downloads under the caps of `bandwidth_limit`, shared with every other
+    /// configuration holding a clone of it.
+    pub fn with_bandwidth_limit(mut self, bandwidth_limit: BandwidthLimit) -> Self {
+        self.bandwidth_limit = bandwidth_limit;
+        self
+    }
+
+    /// Check the free space before downloading according to `space_check`.
+    pub fn with_space_check(mut self, space_check: SpaceCheck) -> Self {
+        self.space_check = space_check;
+        self
+    }
+
     pub async fn create_output_dir(&self) -> Result<(), Box<RssDumpError>> {
         info!("Checking {}...", self.get_output_display());
 
@@ -110,7 +419,7 @@ impl<'input_life> DumpConfig<'input_life> {
         is_path_writable(&self.output)
     }
 
-    pub fn get_output_display(&self) -> path::Display {
+    pub fn get_output_display(&self) -> path::Display<'_> {
         self.output.display()
     }
 
@@ -118,6 +427,16 @@ impl<'input_life> DumpConfig<'input_life> {
         self.output.as_path()
     }
 
+    /// Directory where downloads and their state live. For file outputs this is the parent
+    /// directory of the file.
+    pub fn get_output_dir(&self) -> &Path {
+        if self.output_is_file {
+            self.output.parent().unwrap_or_else(|| Path::new("."))
+        } else {
+            self.output.as_path()
+        }
+    }
+
     pub fn get_n_downloads(&self) -> usize {
         self.n_downloads
     }
@@ -125,4 +444,79 @@ impl<'input_life> DumpConfig<'input_life> {
     pub fn get_feed(&self) -> &str {
         self.feed
     }
+
+    pub fn get_host_downloads(&self) -> Option<usize> {
+        self.host_downloads
+    }
+
+    pub fn get_segments(&self) -> usize {
+        self.segments
+    }
+
+    pub fn get_headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn get_client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    pub fn get_filename_template(&self) -> Option<&FilenameTemplate> {
+        self.filename_template.as_ref()
+    }
+
+    pub fn get_sanitize_policy(&self) -> SanitizePolicy {
+        self.sanitize_policy
+    }
+
+    pub fn get_retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub fn get_bandwidth_limit(&self) -> &BandwidthLimit {
+        &self.bandwidth_limit
+    }
+
+    pub fn get_space_check(&self) -> SpaceCheck {
+        self.space_check
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_overrides_defaults() {
+        let config = ConfigFile::read_from(
+            r#"
+            [defaults]
+            ndownloads = 4
+            query = "new"
+            headers = { User-Agent = "dumptruckrss", Accept = "*/*" }
+
+            [profiles.cheese]
+            url = "http://example.com/cheese.xml"
+            query = "title:cheese"
+            headers = { Accept = "application/rss+xml" }
+            "#,
+        )
+        .unwrap();
+
+        let profile = config.profile(Some("cheese")).unwrap();
+        assert_eq!(
+            profile.url.as_deref(),
+            Some("http://example.com/cheese.xml")
+        );
+        assert_eq!(profile.query.as_deref(), Some("title:cheese"));
+        assert_eq!(profile.ndownloads, Some(4));
+        assert_eq!(profile.timeout, None);
+        assert_eq!(profile.headers["Accept"], "application/rss+xml");
+        assert_eq!(profile.headers["User-Agent"], "dumptruckrss");
+        assert_eq!(profile.header_map().unwrap().len(), 2);
+
+        assert_eq!(config.profile(None).unwrap().query.as_deref(), Some("new"));
+        assert!(config.profile(Some("wine")).is_err());
+        assert!(ConfigFile::read_from("[defaults]\nndownload = 4").is_err());
+    }
 }
@@ -10,13 +10,27 @@ pub enum RssDumpError {
     TokioIo(tokio_io::Error),
     NotEnoughFreeSpace { required: u64, available: u64 },
     Rss(rss::Error),
+    Atom(atom_syndication::Error),
+    UnknownFeedFormat,
     ParseInt(std::num::ParseIntError),
     OutputIsDirectory(PathBuf),
     OutputDirIsNotReadable(PathBuf),
     OutputDirIsNotWritable(PathBuf),
+    OutputDirIsLocked(PathBuf),
     Query(QueryError),
     Reqwest(reqwest::Error),
     RssChannelBuilder(String),
+    Json(serde_json::Error),
+    Xml(quick_xml::Error),
+    Toml(toml::de::Error),
+    UnknownProfile(String),
+    InvalidHeader(String),
+    Duration(humantime::DurationError),
+    InvalidTemplate(String),
+    UnknownSanitizePolicy(String),
+    InvalidRate(String),
+    InvalidSize(String),
+    UnknownSpacePolicy(String),
 }
 
 impl std::error::Error for RssDumpError {}
@@ -42,6 +56,11 @@ impl fmt::Display for RssDumpError {
                 )?;
             }
             RssDumpError::Rss(e) => writeln!(f, "Rss Error: {}", e)?,
+            RssDumpError::Atom(e) => writeln!(f, "Atom Error: {}", e)?,
+            RssDumpError::UnknownFeedFormat => writeln!(
+                f,
+                "Feed Error: the document is not in a supported feed format"
+            )?,
             RssDumpError::ParseInt(e) => writeln!(f, "ParseInt Error: {}", e)?,
             RssDumpError::OutputIsDirectory(o) => writeln!(
                 f,
@@ -58,9 +77,49 @@ impl fmt::Display for RssDumpError {
                 "Output Directory Error: {} is not writable by the current user",
                 o.display()
             )?,
+            RssDumpError::OutputDirIsLocked(o) => writeln!(
+                f,
+                "Output Directory Error: {} is in use by another dumptruckrss process",
+                o.display()
+            )?,
             RssDumpError::Query(e) => writeln!(f, "Query Error: {}", e)?,
             RssDumpError::Reqwest(e) => writeln!(f, "Reqwest Error: {}", e)?,
             RssDumpError::RssChannelBuilder(e) => writeln!(f, "RssChannelBuilder Error: {}", e)?,
+            RssDumpError::Json(e) => writeln!(f, "Json Error: {}", e)?,
+            RssDumpError::Xml(e) => writeln!(f, "Xml Error: {}", e)?,
+            RssDumpError::Toml(e) => writeln!(f, "Toml Error: {}", e)?,
+            RssDumpError::UnknownProfile(p) => writeln!(
+                f,
+                "Config Error: there is no profile named {} in the configuration file",
+                p
+            )?,
+            RssDumpError::InvalidHeader(h) => {
+                writeln!(f, "Config Error: {} is not a valid HTTP header", h)?
+            }
+            RssDumpError::Duration(e) => writeln!(f, "Duration Error: {}", e)?,
+            RssDumpError::InvalidTemplate(t) => {
+                writeln!(f, "Config Error: invalid filename template {}", t)?
+            }
+            RssDumpError::UnknownSanitizePolicy(p) => writeln!(
+                f,
+                "Config Error: {} is not a sanitize policy, expected portable, windows or posix",
+                p
+            )?,
+            RssDumpError::InvalidRate(r) => writeln!(
+                f,
+                "Config Error: {} is not a rate, expected bytes per second like 500K or 2M",
+                r
+            )?,
+            RssDumpError::InvalidSize(s) => writeln!(
+                f,
+                "Config Error: {} is not a size, expected bytes like 500M or 2G",
+                s
+            )?,
+            RssDumpError::UnknownSpacePolicy(p) => writeln!(
+                f,
+                "Config Error: {} is not a space policy, expected fail, trim or ignore",
+                p
+            )?,
         }
 
         Ok(())
@@ -89,6 +148,17 @@ impl From<rss::Error> for Box<RssDumpError> {
     }
 }
 
+impl From<atom_syndication::Error> for RssDumpError {
+    fn from(error: atom_syndication::Error) -> Self {
+        RssDumpError::Atom(error)
+    }
+}
+impl From<atom_syndication::Error> for Box<RssDumpError> {
+    fn from(error: atom_syndication::Error) -> Self {
+        Box::new(RssDumpError::Atom(error))
+    }
+}
+
 impl From<std::num::ParseIntError> for RssDumpError {
     fn from(error: std::num::ParseIntError) -> Self {
         RssDumpError::ParseInt(error)
@@ -132,3 +202,47 @@ impl From<String> for Box<RssDumpError> {
         Box::new(RssDumpError::RssChannelBuilder(error))
     }
 }
+
+impl From<serde_json::Error> for RssDumpError {
+    fn from(error: serde_json::Error) -> Self {
+        RssDumpError::Json(error)
+    }
+}
+impl From<serde_json::Error> for Box<RssDumpError> {
+    fn from(error: serde_json::Error) -> Self {
+        Box::new(RssDumpError::Json(error))
+    }
+}
+
+impl From<quick_xml::Error> for RssDumpError {
+    fn from(error: quick_xml::Error) -> Self {
+        RssDumpError::Xml(error)
+    }
+}
+impl From<quick_xml::Error> for Box<RssDumpError> {
+    fn from(error: quick_xml::Error) -> Self {
+        Box::new(RssDumpError::Xml(error))
+    }
+}
+
+impl From<toml::de::Error> for RssDumpError {
+    fn from(error: toml::de::Error) -> Self {
+        RssDumpError::Toml(error)
+    }
+}
+impl From<toml::de::Error> for Box<RssDumpError> {
+    fn from(error: toml::de::Error) -> Self {
+        Box::new(RssDumpError::Toml(error))
+    }
+}
+
+impl From<humantime::DurationError> for RssDumpError {
+    fn from(error: humantime::DurationError) -> Self {
+        RssDumpError::Duration(error)
+    }
+}
+impl From<humantime::DurationError> for Box<RssDumpError> {
+    fn from(error: humantime::DurationError) -> Self {
+        Box::new(RssDumpError::Duration(error))
+    }
+}
@@ -1,36 +1,192 @@
-#[derive(Debug)]
-pub enum AudioType {
-    Mpeg,
-    Aac,
-    Mp4,
-    Ogg,
-    M4a,
+/// Extension given to files whose type couldn't be detected.
+pub const GENERIC_EXTENSION: &str = "bin";
+
+/// Known MIME types, including common aliases, and the extension their files are stored with.
+const MIME_TYPES: &[(&str, &str)] = &[
+    // Audio
+    ("audio/mpeg", "mp3"),
+    ("audio/mp3", "mp3"),
+    ("audio/mpeg3", "mp3"),
+    ("audio/x-mpeg", "mp3"),
+    ("audio/x-mp3", "mp3"),
+    ("audio/x-mpeg-3", "mp3"),
+    ("audio/aac", "aac"),
+    ("audio/x-aac", "aac"),
+    ("audio/aacp", "aac"),
+    ("audio/mp4", "mp4"),
+    ("audio/x-m4a", "m4a"),
+    ("audio/m4a", "m4a"),
+    ("audio/x-m4b", "m4b"),
+    ("audio/ogg", "ogg"),
+    ("audio/vorbis", "ogg"),
+    ("audio/opus", "opus"),
+    ("audio/flac", "flac"),
+    ("audio/x-flac", "flac"),
+    ("audio/wav", "wav"),
+    ("audio/wave", "wav"),
+    ("audio/x-wav", "wav"),
+    ("audio/webm", "weba"),
+    ("audio/x-ms-wma", "wma"),
+    ("audio/amr", "amr"),
+    // Video
+    ("video/mp4", "mp4"),
+    ("video/x-m4v", "m4v"),
+    ("video/quicktime", "mov"),
+    ("video/webm", "webm"),
+    ("video/ogg", "ogv"),
+    ("video/x-matroska", "mkv"),
+    ("video/mpeg", "mpeg"),
+    ("video/x-msvideo", "avi"),
+    ("video/x-flv", "flv"),
+    ("video/3gpp", "3gp"),
+    ("video/mp2t", "ts"),
+    // Images
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("image/svg+xml", "svg"),
+    ("image/avif", "avif"),
+    // Documents
+    ("application/pdf", "pdf"),
+    ("application/epub+zip", "epub"),
+    ("application/msword", "doc"),
+    (
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "docx",
+    ),
+    ("application/vnd.oasis.opendocument.text", "odt"),
+    ("text/plain", "txt"),
+    ("text/html", "html"),
+    ("text/markdown", "md"),
+    ("application/json", "json"),
+    // Archives
+    ("application/zip", "zip"),
+    ("application/x-zip-compressed", "zip"),
+    ("application/gzip", "gz"),
+    ("application/x-gzip", "gz"),
+    ("application/x-tar", "tar"),
+    ("application/x-bzip2", "bz2"),
+    ("application/x-xz", "xz"),
+    ("application/x-7z-compressed", "7z"),
+    ("application/vnd.rar", "rar"),
+    ("application/x-rar-compressed", "rar"),
+    ("application/x-bittorrent", "torrent"),
+];
+
+/// Extension of an enclosure with the MIME type `mime_type` served from `url`. Servers often
+/// send missing or generic types, so the extension in the URL is used when the type is
+/// unknown.
+pub fn detect_extension(mime_type: &str, url: &str) -> Option<&'static str> {
+    extension_from_mime(mime_type).or_else(|| extension_from_url(url))
 }
 
-impl AudioType {
-    pub fn get_type_from_mime(mime_type: &str) -> Self {
-        match mime_type {
-            "audio/mpeg" => AudioType::Mpeg,
-            "audio/aac" => AudioType::Aac,
-            "audio/ogg" => AudioType::Ogg,
-            "audio/mp4" => AudioType::Mp4,
-            "audio/x-m4a" => AudioType::M4a,
-            _ => panic!("Undetected Audio type: {}", mime_type),
-        }
-    }
+pub fn extension_from_mime(mime_type: &str) -> Option<&'static str> {
+    // Ignore parameters, e.g. "audio/mpeg; charset=binary"
+    let mime_type = mime_type.split(';').next()?.trim().to_lowercase();
 
-    pub fn get_extension_from_type(ty: Self) -> &'static str {
-        match ty {
-            AudioType::Mpeg => "mp3",
-            AudioType::Aac => "aac",
-            AudioType::Ogg => "ogg",
-            AudioType::Mp4 => "mp4",
-            AudioType::M4a => "m4a",
+    MIME_TYPES
+        .iter()
+        .find(|(known, _)| *known == mime_type)
+        .map(|(_, extension)| *extension)
+}
+
+/// Known extension at the end of the path of `url`, ignoring its query and fragment.
+pub fn extension_from_url(url: &str) -> Option<&'static str> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let (_, extension) = url.path_segments()?.next_back()?.rsplit_once('.')?;
+    let extension = extension.to_lowercase();
+
+    MIME_TYPES
+        .iter()
+        .map(|(_, known)| *known)
+        .find(|known| *known == extension)
+}
+
+/// Extension of a file starting with `bytes`, detected from the signatures of known formats.
+pub fn extension_from_content(bytes: &[u8]) -> Option<&'static str> {
+    let starts_with = |offset: usize, signature: &[u8]| {
+        bytes.get(offset..offset + signature.len()) == Some(signature)
+    };
+
+    let extension = match bytes {
+        _ if starts_with(0, b"ID3") => "mp3",
+        // ADTS frames, the raw AAC stream
+        [0xFF, second, ..] if second & 0xF6 == 0xF0 => "aac",
+        // MPEG audio frames
+        [0xFF, second, ..] if second & 0xE0 == 0xE0 => "mp3",
+        _ if starts_with(0, b"OggS") => "ogg",
+        _ if starts_with(0, b"fLaC") => "flac",
+        _ if starts_with(0, b"RIFF") && starts_with(8, b"WAVE") => "wav",
+        _ if starts_with(0, b"RIFF") && starts_with(8, b"AVI ") => "avi",
+        _ if starts_with(0, b"RIFF") && starts_with(8, b"WEBP") => "webp",
+        _ if starts_with(4, b"ftypM4A") => "m4a",
+        _ if starts_with(4, b"ftypqt") => "mov",
+        _ if starts_with(4, b"ftyp") => "mp4",
+        _ if starts_with(0, &[0x1A, 0x45, 0xDF, 0xA3]) => {
+            if bytes.windows(4).any(|window| window == b"webm") {
+                "webm"
+            } else {
+                "mkv"
+            }
         }
+        _ if starts_with(0, b"FLV") => "flv",
+        _ if starts_with(0, b"%PDF") => "pdf",
+        _ if starts_with(0, &[0xFF, 0xD8, 0xFF]) => "jpg",
+        _ if starts_with(0, b"\x89PNG") => "png",
+        _ if starts_with(0, b"GIF8") => "gif",
+        _ if starts_with(0, b"PK\x03\x04") && starts_with(30, b"mimetypeapplication/epub+zip") => {
+            "epub"
+        }
+        _ if starts_with(0, b"PK\x03\x04") => "zip",
+        _ if starts_with(0, &[0x1F, 0x8B]) => "gz",
+        _ if starts_with(0, b"BZh") => "bz2",
+        _ if starts_with(0, b"\xFD7zXZ\x00") => "xz",
+        _ if starts_with(0, b"7z\xBC\xAF\x27\x1C") => "7z",
+        _ if starts_with(0, b"Rar!") => "rar",
+        _ if starts_with(257, b"ustar") => "tar",
+        _ => return None,
+    };
+
+    Some(extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_extensions() {
+        assert_eq!(detect_extension("audio/mpeg", ""), Some("mp3"));
+        assert_eq!(
+            detect_extension("Audio/X-MPEG; charset=binary", ""),
+            Some("mp3")
+        );
+        assert_eq!(detect_extension("video/mp4", ""), Some("mp4"));
+        assert_eq!(
+            detect_extension("application/octet-stream", "http://a.com/ep.PDF?dl=1#page"),
+            Some("pdf")
+        );
+        assert_eq!(detect_extension("", "http://a.com/ep.m4a"), Some("m4a"));
+        assert_eq!(detect_extension("", "http://a.com/episode"), None);
+        assert_eq!(detect_extension("", "http://a.com/ep.unknown"), None);
     }
 
-    pub fn get_extension_from_mime(mime_type: &str) -> &'static str {
-        let ty = Self::get_type_from_mime(mime_type);
-        Self::get_extension_from_type(ty)
+    #[test]
+    fn sniff_extensions() {
+        assert_eq!(extension_from_content(b"ID3\x04\x00"), Some("mp3"));
+        assert_eq!(extension_from_content(&[0xFF, 0xFB, 0x90]), Some("mp3"));
+        assert_eq!(extension_from_content(&[0xFF, 0xF1, 0x50]), Some("aac"));
+        assert_eq!(
+            extension_from_content(b"\x00\x00\x00\x20ftypM4A \x00"),
+            Some("m4a")
+        );
+        assert_eq!(
+            extension_from_content(b"\x00\x00\x00\x20ftypisom\x00"),
+            Some("mp4")
+        );
+        assert_eq!(extension_from_content(b"%PDF-1.7"), Some("pdf"));
+        assert_eq!(extension_from_content(b"<html>"), None);
+        assert_eq!(extension_from_content(b""), None);
     }
 }
@@ -1,36 +1,66 @@
+use super::bandwidth::Throttle;
+use super::cache::{CacheValidators, FeedCache};
 use super::config::DumpConfig;
 use super::error::RssDumpError;
+use super::ext::{self, GENERIC_EXTENSION};
+use super::model::{Channel, Enclosure, Item};
 use super::query::QueryOp;
-use super::utils::create_file_path;
+use super::retry::RetryPolicy;
+use super::sanitize::collision_suffix;
+use super::state::{item_key, DownloadState, ItemRecord};
+use super::utils::{
+    create_file_path, create_part_file_path, create_segments_file_path, hash_file, url_host,
+};
 
-use futures::stream::{self, StreamExt, TryStreamExt};
+use chrono::{DateTime, FixedOffset, Utc};
+use futures::stream::{self, StreamExt};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use once_cell::sync::OnceCell;
 use rayon::prelude::*;
-use reqwest::header::{HeaderValue, CONTENT_LENGTH, RANGE};
+use reqwest::header::{
+    HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, LAST_MODIFIED, RANGE,
+};
 use reqwest::StatusCode;
-use tokio::fs::File;
-use tokio::io as tokio_io;
-use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{Mutex as AsyncMutex, Semaphore};
+use tokio_util::sync::CancellationToken;
 
 use std::boxed::Box;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::{Arc, Mutex, Weak};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
+
+/// An item that failed to download, where it was going to be stored and why it failed.
+pub type FailedDownload = (
+    Weak<Item>,
+    PathBuf,
+    Box<dyn std::error::Error + Send + Sync>,
+);
 
 #[derive(Debug)]
 pub struct Feed<'config> {
     title: String,
-    full_download_list: Vec<Arc<rss::Item>>,
+    full_download_list: Vec<Arc<Item>>,
     config: &'config DumpConfig<'config>,
-    channel: rss::Channel,
+    channel: Channel,
+    state: RwLock<DownloadState>,
+    file_paths: OnceCell<Vec<PathBuf>>,
+    fetched_at: DateTime<Utc>,
 }
 
 impl<'config> Feed<'config> {
     pub async fn new(
-        orig_channel: rss::Channel,
+        orig_channel: Channel,
         config: &'config DumpConfig<'config>,
-    ) -> Feed<'config> {
-        Self {
+    ) -> Result<Feed<'config>, Box<RssDumpError>> {
+        Ok(Self {
             title: orig_channel.title().to_owned(),
             full_download_list: orig_channel
                 .items()
@@ -39,199 +69,674 @@ impl<'config> Feed<'config> {
                 .collect(),
             config,
             channel: orig_channel,
-        }
+            state: RwLock::new(DownloadState::load(config.get_output_dir())?),
+            file_paths: OnceCell::new(),
+            fetched_at: Utc::now(),
+        })
     }
 
-    async fn get_content_length(item: &rss::Enclosure) -> Result<u64, Box<dyn std::error::Error>> {
-        let response = reqwest::Client::new().head(item.url()).send().await?;
-        let length = response
-            .headers()
-            .get(CONTENT_LENGTH)
-            .ok_or("response doesn't include the content length")?;
-        let length =
-            u64::from_str(length.to_str()?).map_err(|_| "invalid Content-Length header")?;
+    /// Ask the server for the length of an enclosure and whether it serves byte ranges.
+    /// Servers that reject HEAD requests or leave headers out yield an unknown length, and
+    /// whether they serve byte ranges is left to the response of the download itself.
+    async fn probe(&self, item: &Enclosure) -> RemoteFile {
+        let request = self
+            .config
+            .get_client()
+            .head(item.url())
+            .headers(self.config.get_headers().clone());
+
+        let response = match self.config.get_retry_policy().send(request).await {
+            Ok(response) if response.status().is_success() => response,
+            Ok(response) => {
+                info!("HEAD {} failed: {}", item.url(), response.status());
+                return RemoteFile::unknown();
+            }
+            Err(e) => {
+                info!("HEAD {} failed: {}", item.url(), e);
+                return RemoteFile::unknown();
+            }
+        };
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+        };
 
-        Ok(length)
+        RemoteFile {
+            length: header(CONTENT_LENGTH).and_then(|length| u64::from_str(length).ok()),
+            // Servers that don't say anything may still serve ranges, which is checked on
+            // the first response
+            accepts_ranges: header(ACCEPT_RANGES) != Some("none"),
+            advertises_ranges: header(ACCEPT_RANGES) == Some("bytes"),
+        }
     }
 
     pub fn build_list_from_query<'a>(
         &mut self,
-        queries: &[QueryOp<'a>],
-    ) -> Result<Vec<Weak<rss::Item>>, Box<RssDumpError>> {
+        query: &QueryOp<'a>,
+    ) -> Result<Vec<Weak<Item>>, Box<RssDumpError>> {
         Ok(self
             .full_download_list
             .par_iter()
             .enumerate()
-            .filter(|(i, item)| {
-                queries
-                    .iter()
-                    .map(|func| func((item, *i, self)))
-                    .fold(true, |res, query_result| res & query_result)
-            })
+            .filter(|(i, item)| query((item, *i, self)))
             .map(|(_, item)| Arc::downgrade(item))
             .collect())
     }
 
+    /// The items matching `query` that can be downloaded. Items without an enclosure, common
+    /// in Atom and JSON feeds, are reported as skipped.
+    pub fn build_download_list<'a>(
+        &mut self,
+        query: &QueryOp<'a>,
+    ) -> Result<Vec<Weak<Item>>, Box<RssDumpError>> {
+        let mut download_list = self.build_list_from_query(query)?;
+        download_list.retain(|item| match item.upgrade() {
+            Some(item) if item.enclosure().is_none() => {
+                println!(
+                    "Skipping {}. It has no enclosure",
+                    item.title().unwrap_or("(untitled)")
+                );
+                false
+            }
+            _ => true,
+        });
+
+        Ok(download_list)
+    }
+
     pub async fn download_items(
         &self,
-        download_list: &[Weak<rss::Item>],
-    ) -> Vec<(Weak<rss::Item>, PathBuf, Box<dyn std::error::Error>)> {
-        let failed_downs = Arc::new(Mutex::new(vec![]));
-
-        let m = Arc::new(MultiProgress::new());
-        let sty = ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] {bar:40.cyan/blue} {percent:>3}% {msg}")
-            .progress_chars("##-");
-        let pb_main = Arc::new(m.add(ProgressBar::new(download_list.len() as u64)));
-        pb_main.set_style(sty);
-        pb_main.enable_steady_tick(1000);
-
-        let m_sentinel = Arc::clone(&m);
-        std::thread::spawn(move || m_sentinel.join_and_clear().unwrap());
-
-        stream::iter(download_list.iter().rev())
-            .for_each_concurrent(self.config.n_downloads, |epi| {
-                let name = epi
-                    .upgrade()
-                    .unwrap()
-                    .title()
-                    .unwrap_or("Boilerplate Episode Title")
-                    .to_owned();
-
-                let new_file = create_file_path(
-                    &self.config.output,
-                    epi.upgrade().unwrap().enclosure().unwrap().mime_type(),
-                    &name,
+        download_list: &[Weak<Item>],
+        cancel: &CancellationToken,
+    ) -> Vec<FailedDownload> {
+        download_feeds(
+            &[(self, download_list)],
+            self.config.n_downloads,
+            self.config.get_host_downloads(),
+            cancel,
+        )
+        .await
+        .pop()
+        .unwrap_or_default()
+    }
+
+    /// Download an enclosure to `new_file`, returning where it was stored and the number of
+    /// bytes stored. Files with the generic extension get the one detected from their content.
+    ///
+    /// The enclosure is downloaded in chunks when the server tells its length and serves byte
+    /// ranges, and in a single streamed request otherwise.
+    async fn download_and_store_item(
+        &self,
+        item: &Enclosure,
+        mut new_file: PathBuf,
+        m: Arc<MultiProgress>,
+        name: String,
+        cancel: &CancellationToken,
+    ) -> Result<(PathBuf, u64), Box<dyn std::error::Error + Send + Sync>> {
+        let remote = unless_cancelled(cancel, self.probe(item)).await?;
+
+        // Create progress bar, a spinner when the length is unknown
+        let pb = match remote.length {
+            Some(length) => {
+                let pb = m.add(ProgressBar::new(length).with_message(name.clone()));
+                pb.set_style(
+                    ProgressStyle::default_bar()
+                        .template("{bar:40.cyan/blue} {percent:>3}% {bytes_per_sec:>14} {msg}")
+                        .progress_chars("##-"),
+                );
+                pb
+            }
+            None => {
+                let pb = m.add(ProgressBar::new_spinner().with_message(name.clone()));
+                pb.set_style(
+                    ProgressStyle::default_spinner()
+                        .template("{spinner:.cyan} {bytes:>10} {bytes_per_sec:>14} {msg}"),
                 );
+                pb
+            }
+        };
+        pb.enable_steady_tick(1000);
+
+        // Templates may place the file in a subdirectory
+        if let Some(parent) = new_file.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // Open the partial file, resuming from whatever a previous run left behind. It's not
+        // opened for appending, so segmented downloads can write anywhere in it
+        let part_file = create_part_file_path(&new_file);
+        let mut output_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&part_file)
+            .await?;
+
+        let mut downloaded = output_file.metadata().await?.len();
+
+        // A segmented download that was interrupted may have completed chunks after a gap,
+        // which are listed next to the partial file
+        let segments_file = create_segments_file_path(&part_file);
+        let mut completed = fs::read_to_string(&segments_file)
+            .await
+            .ok()
+            .map(|ranges| CompletedRanges::parse(&ranges));
+
+        if remote.length.map_or(false, |length| downloaded > length)
+            || (downloaded > 0 && !remote.accepts_ranges)
+        {
+            info!(
+                "{} can't be resumed from the remote file. Restarting download",
+                part_file.display()
+            );
+            output_file.set_len(0).await?;
+            downloaded = 0;
+            if completed.take().is_some() {
+                fs::remove_file(&segments_file).await?;
+            }
+        }
+        if let Some(completed) = &completed {
+            downloaded = downloaded.min(completed.contiguous());
+        }
+        if downloaded > 0 {
+            info!("Resuming {} from byte {}", part_file.display(), downloaded);
+        }
+        output_file.seek(SeekFrom::Start(downloaded)).await?;
+        pb.set_position(downloaded);
+
+        let mut sink = Sink {
+            file: &mut output_file,
+            path: &part_file,
+            pb: &pb,
+            throttle: self.config.get_bandwidth_limit().throttle(item.url()),
+        };
+        let transferred = self
+            .transfer(item, remote, &mut sink, downloaded, completed, cancel)
+            .await;
+
+        // Whatever arrived stays in the partial file, even if the transfer was interrupted
+        output_file.flush().await?;
+        drop(output_file);
+        let length = transferred?;
+
+        // Keep incomplete downloads as partial files, so a retry resumes them
+        let stored = fs::metadata(&part_file).await?.len();
+        if length.map_or(false, |length| stored != length) {
+            return Err(Box::new(futures::io::Error::new(
+                futures::io::ErrorKind::Other,
+                format!(
+                    "Stored {} of {} bytes ({})",
+                    stored,
+                    length.unwrap_or_default(),
+                    name
+                ),
+            )));
+        }
+        if let Some(expected) = item.length().filter(|expected| *expected != stored) {
+            info!(
+                "{} is {} bytes long, but its enclosure says {}",
+                name, stored, expected
+            );
+        }
+
+        if new_file.extension() == Some(GENERIC_EXTENSION.as_ref()) {
+            let mut head = vec![0; 512];
+            let read = fs::File::open(&part_file).await?.read(&mut head).await?;
+            if let Some(extension) = ext::extension_from_content(&head[..read]) {
+                info!("Detected {} as a .{} file", name, extension);
+                new_file.set_extension(extension);
+            }
+        }
+
+        fs::rename(&part_file, &new_file).await?;
+
+        pb.finish_and_clear();
+
+        Ok((new_file, stored))
+    }
 
-                // Perform download
-                let failed_downs = Arc::clone(&failed_downs);
-                let local_m = Arc::clone(&m);
-                let local_pb_main = Arc::clone(&pb_main);
+    /// Append the enclosure to the `downloaded` bytes of `sink`, in chunks when the server
+    /// serves byte ranges. Segmented downloads also keep the `completed` ranges of an earlier
+    /// one. Returns the length of the whole file, when it's known.
+    async fn transfer(
+        &self,
+        item: &Enclosure,
+        remote: RemoteFile,
+        sink: &mut Sink<'_>,
+        downloaded: u64,
+        completed: Option<CompletedRanges>,
+        cancel: &CancellationToken,
+    ) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        match remote.length {
+            Some(length)
+                if self.config.get_segments() > 1
+                    && remote.advertises_ranges
+                    && (completed.is_some()
+                        || length.saturating_sub(downloaded) > u64::from(CHUNK_SIZE)) =>
+            {
+                let completed = completed.unwrap_or_else(|| CompletedRanges::up_to(downloaded));
+                self.transfer_segments(item, sink, completed, length, cancel)
+                    .await?;
+                return Ok(Some(length));
+            }
+            _ => (),
+        }
+
+        // Only the bytes before the first gap of an earlier segmented download can be appended to
+        if completed.is_some() {
+            sink.file.set_len(downloaded).await?;
+            fs::remove_file(create_segments_file_path(sink.path)).await?;
+        }
+
+        let client = self.config.get_client();
+        let retry_policy = self.config.get_retry_policy();
+
+        let chunks = match remote.length {
+            Some(length) if remote.accepts_ranges && length > 0 => {
+                Some(PartialRangeIter::new(downloaded, length - 1, CHUNK_SIZE)?)
+            }
+            _ => None,
+        };
+        let single_request = chunks.is_none();
+        let mut whole_response = None;
+        let mut offset = downloaded;
+
+        for (range, last) in chunks.into_iter().flatten() {
+            let request = client
+                .get(item.url())
+                .headers(self.config.get_headers().clone())
+                .header(RANGE, range);
+            let response = unless_cancelled(cancel, retry_policy.send(request)).await??;
+
+            let status = response.status();
+            if status == StatusCode::OK {
+                // The whole file is coming, so download it in one go instead
+                info!("{} doesn't serve byte ranges", item.url());
+                whole_response = Some(response);
+                break;
+            } else if status != StatusCode::PARTIAL_CONTENT {
+                return Err(Box::new(futures::io::Error::new(
+                    futures::io::ErrorKind::Other,
+                    format!("Unexpected server response: {} ({})", status, item.url()),
+                )));
+            }
+
+            check_content_range(&response, offset)?;
+            write_body(response, sink, cancel).await?;
+            offset += last + 1;
+        }
+
+        if single_request {
+            let mut request = client
+                .get(item.url())
+                .headers(self.config.get_headers().clone());
+            if downloaded > 0 {
+                request = request.header(RANGE, format!("bytes={}-", downloaded));
+            }
+            let response = unless_cancelled(cancel, retry_policy.send(request)).await??;
+            whole_response = Some(response.error_for_status()?);
+        }
+
+        match whole_response {
+            Some(response) => Ok(stream_response(response, sink, downloaded, cancel)
+                .await?
+                .or(remote.length)),
+            None => Ok(remote.length),
+        }
+    }
+
+    /// Download the bytes of the enclosure missing from the `completed` ranges in chunks,
+    /// fetching as many of them at once as the configured segments and writing each at its
+    /// offset as it arrives. A chunk that fails for good stops the transfer.
+    ///
+    /// The completed ranges are kept in a file next to the partial file until the transfer
+    /// succeeds, so resuming it only fetches the chunks that are still missing.
+    async fn transfer_segments(
+        &self,
+        item: &Enclosure,
+        sink: &mut Sink<'_>,
+        mut completed: CompletedRanges,
+        length: u64,
+        cancel: &CancellationToken,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let segments_file = create_segments_file_path(sink.path);
+        fs::write(&segments_file, completed.to_string()).await?;
+        sink.pb.set_position(completed.total());
+
+        // Offset and size of every chunk still missing
+        let chunk_size = u64::from(CHUNK_SIZE);
+        let chunks: Vec<(u64, u64)> = completed
+            .gaps(length)
+            .into_iter()
+            .flat_map(|(start, end)| {
+                std::iter::successors(Some(start), move |offset| {
+                    Some(offset + chunk_size).filter(|next| *next < end)
+                })
+                .map(move |offset| (offset, chunk_size.min(end - offset)))
+            })
+            .collect();
+
+        // Stops the other chunks once one of them fails for good
+        let stop = cancel.child_token();
+        let file = AsyncMutex::new(&mut *sink.file);
+        let (pb, throttle) = (sink.pb, &sink.throttle);
+
+        let mut fetches = stream::iter(chunks.iter().enumerate())
+            .map(|(i, (offset, size))| {
+                let (file, stop) = (&file, &stop);
 
                 async move {
-                    match self
-                        .download_and_store_item(
-                            epi.upgrade().unwrap().enclosure().unwrap(),
-                            new_file.clone(),
-                            local_m,
-                            name,
-                        )
-                        .await
-                    {
-                        Ok(_) => {
-                            local_pb_main.inc(1);
-                            std::thread::sleep(std::time::Duration::from_millis(
-                                self.config.timeout as u64,
-                            ));
-                        }
-                        Err(e) => {
-                            failed_downs
-                                .lock()
-                                .unwrap()
-                                .push((epi.clone(), new_file, e));
-                        }
+                    if stop.is_cancelled() {
+                        return Err(Box::new(Cancelled).into());
                     }
+                    self.fetch_segment(item, *offset, *size, file, pb, throttle, stop)
+                        .await?;
+
+                    Ok::<_, Box<dyn std::error::Error + Send + Sync>>(i)
                 }
             })
-            .await;
+            .buffer_unordered(self.config.get_segments());
 
-        pb_main.finish_with_message("Downloads Complete!");
+        let mut result = Ok(());
 
-        if failed_downs.lock().unwrap().len() > 0 {
-            info!("{} Failed Downloads", failed_downs.lock().unwrap().len());
-            for (failed_item, _, error) in failed_downs.lock().unwrap().iter() {
-                info!(
-                    "\tURL: {:?}; Error: {:?}",
-                    failed_item.upgrade().unwrap().enclosure().unwrap().url(),
-                    error
-                );
+        // Wait for every chunk even after a failure, so the ones that complete are kept
+        while let Some(fetched) = fetches.next().await {
+            match fetched {
+                Ok(i) => {
+                    let (offset, size) = chunks[i];
+                    completed.insert(offset, offset + size);
+                    fs::write(&segments_file, completed.to_string()).await?;
+                }
+                Err(e) => {
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                    stop.cancel();
+                }
             }
         }
 
-        Arc::try_unwrap(failed_downs).unwrap().into_inner().unwrap()
+        if result.is_ok() {
+            fs::remove_file(&segments_file).await?;
+        }
+
+        result
     }
 
-    async fn download_and_store_item(
+    /// Fetch the `size` bytes of the enclosure from `offset`, writing them to `file` as they
+    /// arrive. A chunk whose request or body fails is fetched again from the first byte that
+    /// didn't arrive, as many times as the retry policy allows.
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch_segment(
         &self,
-        item: &rss::Enclosure,
-        new_file: PathBuf,
-        m: Arc<MultiProgress>,
-        name: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        // Get file size
-        let length = Feed::get_content_length(item).await.unwrap();
-
-        // Create progress bar
-        let pb = m.add(ProgressBar::new(length).with_message(name.clone()));
-        let sty = ProgressStyle::default_bar()
-            .template("{bar:40.cyan/blue} {percent:>3}% {bytes_per_sec:>14} {msg}")
-            .progress_chars("##-");
-        pb.set_style(sty);
-        pb.enable_steady_tick(1000);
-
-        const CHUNK_SIZE: u32 = 5 * 1024 * 1024;
-        let tries = 20;
-
-        // Create file
-        let mut output_file = File::create(new_file).await?;
+        item: &Enclosure,
+        offset: u64,
+        size: u64,
+        file: &AsyncMutex<&mut fs::File>,
+        pb: &ProgressBar,
+        throttle: &Throttle,
+        cancel: &CancellationToken,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let retry_policy = self.config.get_retry_policy();
+        let mut received = 0;
+        let mut attempt = 0;
 
-        // Get file
-        let client = reqwest::Client::new();
-        for (range, chunk) in PartialRangeIter::new(0, length - 1, CHUNK_SIZE)? {
-            let mut retry_counter = 1;
-            pb.set_message(name.clone());
+        loop {
+            attempt += 1;
+            let range = format!("bytes={}-{}", offset + received, offset + size - 1);
 
-            loop {
-                let response = client
+            let fetched: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+                let request = self
+                    .config
+                    .get_client()
                     .get(item.url())
-                    .header(RANGE, range.clone())
-                    .send()
-                    .await?;
+                    .headers(self.config.get_headers().clone())
+                    .header(RANGE, range.as_str());
+                let response = unless_cancelled(cancel, retry_policy.send(request)).await??;
+
+                if response.status() != StatusCode::PARTIAL_CONTENT {
+                    return Err(futures::io::Error::new(
+                        futures::io::ErrorKind::Other,
+                        format!(
+                            "Unexpected server response: {} ({})",
+                            response.status(),
+                            item.url()
+                        ),
+                    )
+                    .into());
+                }
+                check_content_range(&response, offset + received)?;
 
-                let status = response.status();
-                if !(status == StatusCode::OK || status == StatusCode::PARTIAL_CONTENT) {
-                    pb.set_message(format!(
-                        "Try {} of {}. Retrying in {}ms! Unexpected server response: {} ({})",
-                        retry_counter,
-                        tries,
-                        retry_counter * self.config.timeout,
-                        status,
-                        name
-                    ));
-                    retry_counter += 1;
-                    std::thread::sleep(std::time::Duration::from_millis(
-                        (retry_counter * self.config.timeout) as u64,
-                    ));
-                    if retry_counter > tries {
-                        return Err(Box::new(futures::io::Error::new(
+                let mut body = response.bytes_stream();
+                while let Some(chunk) = unless_cancelled(cancel, body.next()).await? {
+                    let chunk = chunk?;
+                    if received + chunk.len() as u64 > size {
+                        return Err(futures::io::Error::new(
                             futures::io::ErrorKind::Other,
-                            format!("Unexpected server response: {} ({})", status, name),
-                        )));
+                            format!("Received more than {} bytes from byte {}", size, offset),
+                        )
+                        .into());
                     }
-                    continue;
+
+                    let mut file = file.lock().await;
+                    file.seek(SeekFrom::Start(offset + received)).await?;
+                    file.write_all(&chunk).await?;
+                    // Only count the chunk as written once it is
+                    file.flush().await?;
+                    drop(file);
+
+                    received += chunk.len() as u64;
+                    pb.inc(chunk.len() as u64);
+                    unless_cancelled(cancel, throttle.consume(chunk.len())).await?;
                 }
 
-                pb.inc(chunk);
+                if received != size {
+                    return Err(futures::io::Error::new(
+                        futures::io::ErrorKind::Other,
+                        format!(
+                            "Received {} of {} bytes from byte {}",
+                            received, size, offset
+                        ),
+                    )
+                    .into());
+                }
 
-                // Write file to disk
-                tokio_io::copy(
-                    &mut response
-                        .bytes_stream()
-                        .map_err(|e| futures::io::Error::new(futures::io::ErrorKind::Other, e))
-                        .into_async_read()
-                        .compat(),
-                    &mut output_file,
-                )
-                .await?;
+                Ok(())
+            }
+            .await;
 
-                break;
+            match fetched {
+                Ok(()) => return Ok(()),
+                Err(e) if cancel.is_cancelled() || attempt >= retry_policy.get_max_attempts() => {
+                    // What arrived is fetched again by the next transfer
+                    pb.set_position(pb.position().saturating_sub(received));
+                    info!("{} of {} failed: {}", range, item.url(), e);
+                    return Err(e);
+                }
+                Err(e) => {
+                    info!(
+                        "{} of {} failed, retrying from byte {}: {}",
+                        range,
+                        item.url(),
+                        offset + received,
+                        e
+                    );
+                    let delay = retry_policy.delay(attempt, None);
+                    unless_cancelled(cancel, tokio::time::sleep(delay)).await?;
+                }
             }
         }
+    }
 
-        pb.finish_and_clear();
+    /// Bytes still to download for `item`: the length of its enclosure, asked to the server
+    /// when the feed doesn't tell it, minus what a previous run left in its partial file.
+    pub async fn remaining_size(&self, item: &Item) -> Option<u64> {
+        let enclosure = item.enclosure()?;
+        let length = match enclosure.length().filter(|length| *length > 0) {
+            Some(length) => length,
+            None => self.probe(enclosure).await.length?,
+        };
 
-        Ok(())
+        let part_file = create_part_file_path(&self.file_path(item));
+        let partial = match fs::read_to_string(create_segments_file_path(&part_file)).await {
+            Ok(ranges) => CompletedRanges::parse(&ranges).total(),
+            Err(_) => fs::metadata(&part_file)
+                .await
+                .map_or(0, |metadata| metadata.len()),
+        };
+
+        Some(length.saturating_sub(partial))
+    }
+
+    /// Store a successful download in the state of the output directory.
+    async fn record_download(
+        &self,
+        item: &Item,
+        path: &Path,
+        size: u64,
+    ) -> Result<(), Box<RssDumpError>> {
+        let guid = match item_key(item) {
+            Some(guid) => guid.to_owned(),
+            None => return Ok(()),
+        };
+
+        let record = ItemRecord {
+            guid,
+            url: item.enclosure().map_or("", Enclosure::url).to_owned(),
+            // Relative to the output directory so the whole directory can be moved
+            path: path
+                .strip_prefix(self.config.get_output_dir())
+                .unwrap_or(path)
+                .to_path_buf(),
+            size,
+            sha256: hash_file(path).await?,
+            downloaded_at: chrono::Utc::now(),
+        };
+
+        let mut state = self.state.write().unwrap();
+        state.insert(record);
+        state.save()
+    }
+
+    /// Where `item` is stored in the output directory. Named after the filename template, if
+    /// there is one, or after the item's title, and made valid by the sanitize policy.
+    pub fn file_path(&self, item: &Item) -> PathBuf {
+        match self.number_of(item) {
+            Some(number) => self.file_paths()[number].clone(),
+            None => self.config.get_output_dir().join(
+                self.config
+                    .get_sanitize_policy()
+                    .sanitize_path(&self.unsanitized_file_path(item, 0), ""),
+            ),
+        }
+    }
+
+    /// Paths of every item in the feed. An item named like an older one gets a suffix with a
+    /// hash of its GUID, so it doesn't overwrite it.
+    fn file_paths(&self) -> &[PathBuf] {
+        self.file_paths.get_or_init(|| {
+            let policy = self.config.get_sanitize_policy();
+            let mut taken = HashSet::new();
+
+            // Items are listed from newest to oldest, so older items keep their names
+            let mut file_paths: Vec<PathBuf> = self
+                .full_download_list
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(number, item)| {
+                    let unsanitized = self.unsanitized_file_path(item, number);
+                    let mut file_path = policy.sanitize_path(&unsanitized, "");
+
+                    if !taken.insert(policy.collision_key(&file_path)) {
+                        let suffix = collision_suffix(item_key(item), number);
+                        file_path = policy.sanitize_path(&unsanitized, &suffix);
+                        taken.insert(policy.collision_key(&file_path));
+                    }
+
+                    self.config.get_output_dir().join(file_path)
+                })
+                .collect();
+
+            file_paths.reverse();
+            file_paths
+        })
+    }
+
+    fn unsanitized_file_path(&self, item: &Item, number: usize) -> PathBuf {
+        let extension = self.extension_of(item);
+
+        match self.config.get_filename_template() {
+            Some(template) => template.render(item, number, self.title(), &extension),
+            None => create_file_path(
+                Path::new(""),
+                &extension,
+                item.title().unwrap_or("Boilerplate Episode Title"),
+            ),
+        }
+    }
+
+    /// Extension the enclosure of `item` is stored with. When neither its MIME type nor its
+    /// URL tell the type, it's the extension detected when it was downloaded, if it was.
+    fn extension_of(&self, item: &Item) -> String {
+        item.enclosure()
+            .and_then(|enclosure| ext::detect_extension(enclosure.mime_type(), enclosure.url()))
+            .map(str::to_owned)
+            .or_else(|| {
+                let state = self.state.read().unwrap();
+                let record = state.get(item_key(item)?)?;
+                Some(record.path.extension()?.to_string_lossy().into_owned())
+            })
+            .unwrap_or_else(|| GENERIC_EXTENSION.to_owned())
+    }
+
+    /// Number of `item` in the feed, as used by the `number` query.
+    fn number_of(&self, item: &Item) -> Option<usize> {
+        self.full_download_list
+            .iter()
+            .position(|other| std::ptr::eq(Arc::as_ptr(other), item))
+    }
+
+    /// Whether the item was already downloaded into the output directory, according to the
+    /// download state. Items without a GUID or enclosure are never considered downloaded.
+    pub fn is_downloaded(&self, item: &Item) -> bool {
+        item_key(item).map_or(false, |guid| self.state.read().unwrap().contains(guid))
+    }
+
+    /// When the feed was fetched by the last run that downloaded every item it selected into
+    /// the output directory, according to the download state.
+    pub fn last_run(&self) -> Option<DateTime<Utc>> {
+        self.state.read().unwrap().last_run()
+    }
+
+    /// Whether the feed, not modified since `not_modified_since`, was already fetched by the last
+    /// run that downloaded everything it selected, leaving nothing new to download.
+    pub fn unchanged_since_last_run(&self, not_modified_since: Option<DateTime<Utc>>) -> bool {
+        match (not_modified_since, self.last_run()) {
+            (Some(fetched), Some(last_run)) => fetched <= last_run,
+            _ => false,
+        }
+    }
+
+    /// Record in the download state that every item selected from the feed was downloaded, so
+    /// `since-last-run` selects the items published after it was fetched from now on.
+    pub fn record_run(&self) -> Result<(), Box<RssDumpError>> {
+        let mut state = self.state.write().unwrap();
+        state.set_last_run(self.fetched_at);
+        state.save()
+    }
+
+    /// Records of every download into the output directory, including items no longer in
+    /// the feed.
+    pub fn download_records(&self) -> Vec<ItemRecord> {
+        self.state.read().unwrap().records().cloned().collect()
+    }
+
+    pub fn items(&self) -> impl Iterator<Item = &Item> {
+        self.full_download_list.iter().map(Arc::as_ref)
     }
 
     pub fn title(&self) -> &str {
@@ -242,6 +747,10 @@ impl<'config> Feed<'config> {
         self.full_download_list.len()
     }
 
+    pub fn get_config(&self) -> &DumpConfig<'config> {
+        self.config
+    }
+
     pub fn get_config_output(&self) -> &Path {
         self.config.get_output()
     }
@@ -266,13 +775,393 @@ impl<'config> Feed<'config> {
         self.channel.managing_editor()
     }
 
-    pub fn pub_date(&self) -> Option<&str> {
+    pub fn pub_date(&self) -> Option<&DateTime<FixedOffset>> {
         self.channel.pub_date()
     }
 
-    pub fn categories(&self) -> &[rss::Category] {
+    pub fn categories(&self) -> &[String] {
         self.channel.categories()
     }
+
+    /// Build a new channel with this feed's metadata, the given title and the given items.
+    pub fn create_channel(&self, title: &str, items: &[Weak<Item>]) -> Channel {
+        self.channel.with_items(
+            title,
+            items
+                .iter()
+                .filter_map(Weak::upgrade)
+                .map(|item| (*item).clone())
+                .collect(),
+        )
+    }
+}
+
+/// A fetched feed. When the server answered that it was not modified, it's the cached feed
+/// and `not_modified_since` tells when that was fetched.
+#[derive(Debug)]
+pub struct FetchedChannel {
+    pub channel: Channel,
+    pub not_modified_since: Option<DateTime<Utc>>,
+}
+
+/// Fetch a feed from a url, sending `headers` with the request, or read it from a file.
+///
+/// Fetched feeds are cached along with their `ETag` and `Last-Modified` headers, which are
+/// sent back on the next fetch. When the server answers `304 Not Modified` the cached feed
+/// is used instead.
+pub async fn fetch_channel(
+    feed: &str,
+    is_url: bool,
+    client: &reqwest::Client,
+    headers: &HeaderMap,
+    retry_policy: &RetryPolicy,
+) -> Result<FetchedChannel, Box<RssDumpError>> {
+    if !is_url {
+        let content = fs::read(feed).await?;
+        return Ok(FetchedChannel {
+            channel: Channel::read_from(&content[..])?,
+            not_modified_since: None,
+        });
+    }
+
+    fetch_url(feed, client, headers, retry_policy, &FeedCache::default()).await
+}
+
+/// Fetch the feed at `url`, asking the server whether the copy in `cache` is still current.
+async fn fetch_url(
+    url: &str,
+    client: &reqwest::Client,
+    headers: &HeaderMap,
+    retry_policy: &RetryPolicy,
+    cache: &FeedCache,
+) -> Result<FetchedChannel, Box<RssDumpError>> {
+    let cached = cache.load(url, headers);
+
+    let mut request = client.get(url).headers(headers.clone());
+    if let Some((validators, _)) = &cached {
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag.as_str());
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified.as_str());
+        }
+    }
+
+    let response = retry_policy.send(request).await?;
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some((validators, body)) = cached {
+            info!("{} was not modified, using the cached feed", url);
+            return Ok(FetchedChannel {
+                channel: Channel::read_from(&body)?,
+                not_modified_since: validators.stored_at,
+            });
+        }
+    }
+
+    let response = response.error_for_status()?;
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    let validators = CacheValidators {
+        url: url.to_owned(),
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+        stored_at: Some(Utc::now()),
+    };
+
+    let content = response.bytes().await?;
+    let channel = Channel::read_from(&content[..])?;
+
+    // Only worth caching when the server lets us ask whether it changed
+    if validators.etag.is_some() || validators.last_modified.is_some() {
+        if let Err(e) = cache.store(&validators, headers, &content) {
+            warn!("Failed to cache {}: {}", url, e);
+        }
+    }
+
+    Ok(FetchedChannel {
+        channel,
+        not_modified_since: None,
+    })
+}
+
+/// Download the items of several feeds with a single budget of `n_downloads` concurrent
+/// downloads, of which at most `host_downloads` from the same host. Failed downloads are
+/// returned per feed, in the same order as `jobs`. Items without an enclosure are left out.
+///
+/// When `cancel` is cancelled no new downloads start, and the ones in progress stop and keep
+/// what they downloaded in their partial files. Both fail with [`Cancelled`].
+pub async fn download_feeds(
+    jobs: &[(&Feed<'_>, &[Weak<Item>])],
+    n_downloads: usize,
+    host_downloads: Option<usize>,
+    cancel: &CancellationToken,
+) -> Vec<Vec<FailedDownload>> {
+    let failed_downs = Arc::new(Mutex::new(
+        jobs.iter()
+            .map(|_| vec![])
+            .collect::<Vec<Vec<FailedDownload>>>(),
+    ));
+
+    let downloads = jobs
+        .iter()
+        .enumerate()
+        .flat_map(|(job, (feed, list))| list.iter().rev().map(move |epi| (job, *feed, epi)))
+        .filter(|(_, _, epi)| {
+            epi.upgrade()
+                .map_or(false, |item| item.enclosure().is_some())
+        });
+
+    let m = Arc::new(MultiProgress::new());
+    let sty = ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {bar:40.cyan/blue} {percent:>3}% {msg}")
+        .progress_chars("##-");
+    let total = downloads.clone().count();
+    let pb_main = Arc::new(m.add(ProgressBar::new(total as u64)));
+    pb_main.set_style(sty);
+    pb_main.enable_steady_tick(1000);
+
+    let m_sentinel = Arc::clone(&m);
+    std::thread::spawn(move || m_sentinel.join_and_clear().unwrap());
+
+    // Downloads wait for a slot of their host before taking one of the shared slots, so the
+    // downloads of a busy host don't hold back the ones of other hosts
+    let slots = Semaphore::new(n_downloads.max(1));
+    let host_slots: HashMap<String, Semaphore> = match host_downloads {
+        Some(host_downloads) => downloads
+            .clone()
+            .filter_map(|(_, _, epi)| url_host(epi.upgrade()?.enclosure()?.url()))
+            .map(|host| (host, Semaphore::new(host_downloads.max(1))))
+            .collect(),
+        None => HashMap::new(),
+    };
+    let (slots, host_slots) = (&slots, &host_slots);
+
+    stream::iter(downloads)
+        .for_each_concurrent(None, |(job, feed, epi)| {
+            let name = epi
+                .upgrade()
+                .unwrap()
+                .title()
+                .unwrap_or("Boilerplate Episode Title")
+                .to_owned();
+
+            let new_file = feed.file_path(&epi.upgrade().unwrap());
+
+            // Perform download
+            let failed_downs = Arc::clone(&failed_downs);
+            let local_m = Arc::clone(&m);
+            let local_pb_main = Arc::clone(&pb_main);
+
+            let host_slot = epi
+                .upgrade()
+                .and_then(|item| url_host(item.enclosure()?.url()))
+                .and_then(|host| host_slots.get(&host));
+
+            async move {
+                let permits = unless_cancelled(cancel, async {
+                    let host_permit = match host_slot {
+                        Some(host_slot) => Some(host_slot.acquire().await),
+                        None => None,
+                    };
+                    (host_permit, slots.acquire().await)
+                })
+                .await;
+
+                // Once cancelled, downloads that didn't start are left for the next run
+                let result = if permits.is_err() || cancel.is_cancelled() {
+                    Err(Box::new(Cancelled).into())
+                } else {
+                    feed.download_and_store_item(
+                        epi.upgrade().unwrap().enclosure().unwrap(),
+                        new_file.clone(),
+                        local_m,
+                        name,
+                        cancel,
+                    )
+                    .await
+                };
+
+                match result {
+                    Ok((stored_file, size)) => {
+                        if let Err(e) = feed
+                            .record_download(&epi.upgrade().unwrap(), &stored_file, size)
+                            .await
+                        {
+                            warn!(
+                                "Failed to record {} in the download state: {}",
+                                stored_file.display(),
+                                e
+                            );
+                        }
+
+                        local_pb_main.inc(1);
+                        let pause = Duration::from_millis(feed.config.timeout as u64);
+                        unless_cancelled(cancel, tokio::time::sleep(pause))
+                            .await
+                            .ok();
+                    }
+                    Err(e) => {
+                        failed_downs.lock().unwrap()[job].push((epi.clone(), new_file, e));
+                    }
+                }
+            }
+        })
+        .await;
+
+    pb_main.finish_with_message("Downloads Complete!");
+
+    let failed_downs = Arc::try_unwrap(failed_downs).unwrap().into_inner().unwrap();
+
+    let n_failed: usize = failed_downs.iter().map(Vec::len).sum();
+    if n_failed > 0 {
+        info!("{} Failed Downloads", n_failed);
+        for (failed_item, _, error) in failed_downs.iter().flatten() {
+            info!(
+                "\tURL: {:?}; Error: {:?}",
+                failed_item.upgrade().unwrap().enclosure().unwrap().url(),
+                error
+            );
+        }
+    }
+
+    failed_downs
+}
+
+/// Size of the byte ranges enclosures are downloaded in.
+const CHUNK_SIZE: u32 = 5 * 1024 * 1024;
+
+/// Where a download is written: its partial file, its progress bar and the bandwidth caps it
+/// counts against.
+struct Sink<'a> {
+    file: &'a mut fs::File,
+    path: &'a Path,
+    pb: &'a ProgressBar,
+    throttle: Throttle,
+}
+
+/// Store the body of `response` in `sink`. A partial response is appended to the `downloaded`
+/// bytes already there, while a full one replaces them. Returns the length of the whole file,
+/// when the server tells it.
+async fn stream_response(
+    response: reqwest::Response,
+    sink: &mut Sink<'_>,
+    downloaded: u64,
+    cancel: &CancellationToken,
+) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+    let offset = if response.status() == StatusCode::PARTIAL_CONTENT {
+        check_content_range(&response, downloaded)?;
+        downloaded
+    } else {
+        sink.file.set_len(0).await?;
+        sink.file.seek(SeekFrom::Start(0)).await?;
+        0
+    };
+    let length = response.content_length().map(|length| offset + length);
+
+    if let Some(length) = length {
+        sink.pb.set_length(length);
+    }
+    sink.pb.set_position(offset);
+
+    write_body(response, sink, cancel).await?;
+
+    Ok(length)
+}
+
+/// Fail when the partial `response` doesn't start at byte `offset`, the one it was asked for.
+/// Responses that don't say where they start are trusted.
+fn check_content_range(
+    response: &reqwest::Response,
+    offset: u64,
+) -> Result<(), futures::io::Error> {
+    let start = response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("bytes "))
+        .and_then(|range| range.split('-').next())
+        .and_then(|start| start.trim().parse::<u64>().ok());
+
+    match start {
+        Some(start) if start != offset => Err(futures::io::Error::new(
+            futures::io::ErrorKind::Other,
+            format!(
+                "Asked for the bytes from {} but got the ones from {} ({})",
+                offset,
+                start,
+                response.url()
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Append the body of `response` to `sink` as it arrives, no faster than its caps allow.
+async fn write_body(
+    response: reqwest::Response,
+    sink: &mut Sink<'_>,
+    cancel: &CancellationToken,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut body = response.bytes_stream();
+
+    while let Some(bytes) = unless_cancelled(cancel, body.next()).await? {
+        let bytes = bytes?;
+        sink.file.write_all(&bytes).await?;
+        sink.pb.inc(bytes.len() as u64);
+        unless_cancelled(cancel, sink.throttle.consume(bytes.len())).await?;
+    }
+
+    Ok(())
+}
+
+/// Error of a download stopped by a cancellation.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::error::Error for Cancelled {}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Download cancelled")
+    }
+}
+
+/// Wait for `future`, unless `cancel` is cancelled first.
+async fn unless_cancelled<T>(
+    cancel: &CancellationToken,
+    future: impl Future<Output = T>,
+) -> Result<T, Cancelled> {
+    tokio::select! {
+        output = future => Ok(output),
+        _ = cancel.cancelled() => Err(Cancelled),
+    }
+}
+
+/// What the server tells about an enclosure before downloading it.
+#[derive(Debug, Clone, Copy)]
+struct RemoteFile {
+    length: Option<u64>,
+    accepts_ranges: bool,
+    // Whether the server said it serves byte ranges, rather than not saying otherwise
+    advertises_ranges: bool,
+}
+
+impl RemoteFile {
+    /// An enclosure the server told nothing about. A partial download of it is resumed with
+    /// a single request for the rest, which is appended if the server answers with just that.
+    fn unknown() -> Self {
+        Self {
+            length: None,
+            accepts_ranges: true,
+            advertises_ranges: false,
+        }
+    }
 }
 
 struct PartialRangeIter {
@@ -310,3 +1199,689 @@ impl Iterator for PartialRangeIter {
         }
     }
 }
+
+/// Byte ranges of a partial file that segmented downloads completed, sorted and merged, each
+/// from its first byte up to, but excluding, its end. They are kept in a file next to the
+/// partial file, a `start-end` pair per line.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct CompletedRanges(Vec<(u64, u64)>);
+
+impl CompletedRanges {
+    /// The first `end` bytes of the file.
+    fn up_to(end: u64) -> Self {
+        let mut completed = Self::default();
+        completed.insert(0, end);
+        completed
+    }
+
+    /// Ranges read from a segments file. Lines that aren't ranges are skipped, so their bytes
+    /// are downloaded again.
+    fn parse(ranges: &str) -> Self {
+        let mut completed = Self::default();
+        for line in ranges.lines() {
+            let range = line.trim().split_once('-');
+            if let Some((Ok(start), Ok(end))) =
+                range.map(|(start, end)| (start.parse(), end.parse()))
+            {
+                completed.insert(start, end);
+            }
+        }
+        completed
+    }
+
+    fn insert(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        self.0.push((start, end));
+        self.0.sort_unstable();
+
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(self.0.len());
+        for (start, end) in self.0.drain(..) {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.0 = merged;
+    }
+
+    /// End of the bytes completed without gaps from the start of the file.
+    fn contiguous(&self) -> u64 {
+        match self.0.first() {
+            Some(&(0, end)) => end,
+            _ => 0,
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.0.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// Ranges of the first `length` bytes that were not completed.
+    fn gaps(&self, length: u64) -> Vec<(u64, u64)> {
+        let mut gaps = vec![];
+        let mut offset = 0;
+        for &(start, end) in self.0.iter().take_while(|(start, _)| *start < length) {
+            if start > offset {
+                gaps.push((offset, start));
+            }
+            offset = offset.max(end);
+        }
+        if offset < length {
+            gaps.push((offset, length));
+        }
+        gaps
+    }
+}
+
+impl fmt::Display for CompletedRanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (start, end) in &self.0 {
+            writeln!(f, "{}-{}", start, end)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+    use indicatif::ProgressDrawTarget;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    /// The method of a request made to the test server, where its `Range` starts and ends and
+    /// the validators it sent.
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    struct Request {
+        method: String,
+        range_start: Option<u64>,
+        range_end: Option<u64>,
+        if_none_match: Option<String>,
+        if_modified_since: Option<String>,
+    }
+
+    /// Serve a single file on a local port, answering every request with `respond`. Returns
+    /// its URL and the requests made so far.
+    async fn serve(
+        respond: impl Fn(&Request) -> Vec<u8> + Send + Sync + 'static,
+    ) -> (String, Arc<Mutex<Vec<Request>>>) {
+        serve_slowly(|_| Duration::ZERO, respond).await
+    }
+
+    /// Like `serve`, but taking the `delay` of each GET request to answer it.
+    async fn serve_slowly(
+        delay: impl Fn(&Request) -> Duration + Send + Sync + 'static,
+        respond: impl Fn(&Request) -> Vec<u8> + Send + Sync + 'static,
+    ) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/episode.mp3", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let (delay, respond, log) = (Arc::new(delay), Arc::new(respond), Arc::clone(&requests));
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let (delay, respond, log) =
+                    (Arc::clone(&delay), Arc::clone(&respond), Arc::clone(&log));
+                tokio::spawn(async move {
+                    let mut head = vec![];
+                    let mut byte = [0; 1];
+                    while !head.ends_with(b"\r\n\r\n") && socket.read(&mut byte).await? == 1 {
+                        head.push(byte[0]);
+                    }
+
+                    let head = String::from_utf8_lossy(&head);
+                    let header = |name: &str| {
+                        head.lines().find_map(|line| {
+                            let (header, value) = line.split_once(':')?;
+                            if header.eq_ignore_ascii_case(name) {
+                                Some(value.trim().to_owned())
+                            } else {
+                                None
+                            }
+                        })
+                    };
+                    let range = header("range");
+                    let range = range
+                        .as_deref()
+                        .and_then(|range| range.strip_prefix("bytes="))
+                        .and_then(|range| range.split_once('-'));
+                    let request = Request {
+                        method: head.split(' ').next().unwrap_or_default().to_owned(),
+                        range_start: range.and_then(|(start, _)| start.parse().ok()),
+                        range_end: range.and_then(|(_, end)| end.parse().ok()),
+                        if_none_match: header("if-none-match"),
+                        if_modified_since: header("if-modified-since"),
+                    };
+                    let response = respond(&request);
+                    if request.method == "GET" {
+                        tokio::time::sleep(delay(&request)).await;
+                    }
+                    log.lock().unwrap().push(request);
+                    socket.write_all(&response).await
+                });
+            }
+        });
+
+        (url, requests)
+    }
+
+    /// A response with `status`, `headers` and `body`. HEAD responses tell the length of
+    /// `body` without sending it.
+    fn response(request: &Request, status: &str, headers: &[String], body: &[u8]) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: {}\r\n{}\r\n",
+            status,
+            body.len(),
+            headers
+                .iter()
+                .map(|header| header.clone() + "\r\n")
+                .collect::<String>()
+        )
+        .into_bytes();
+        if request.method != "HEAD" {
+            response.extend_from_slice(body);
+        }
+        response
+    }
+
+    /// Answer `request` for `file` like a server that serves byte ranges.
+    fn serve_ranges(file: &[u8], request: &Request) -> Vec<u8> {
+        match request.range_start {
+            Some(start) if request.method == "GET" => {
+                let end = request.range_end.unwrap_or(file.len() as u64 - 1);
+                response(
+                    request,
+                    "206 Partial Content",
+                    &[format!(
+                        "Content-Range: bytes {}-{}/{}",
+                        start,
+                        end,
+                        file.len()
+                    )],
+                    &file[start as usize..=end as usize],
+                )
+            }
+            _ => response(
+                request,
+                "200 OK",
+                &["Accept-Ranges: bytes".to_owned()],
+                file,
+            ),
+        }
+    }
+
+    /// Download `url` into `file` in up to `segments` segments, after leaving `partial` in its
+    /// partial file.
+    async fn download(
+        url: &str,
+        file: &Path,
+        partial: &[u8],
+        segments: usize,
+    ) -> Result<(PathBuf, u64), Box<dyn std::error::Error + Send + Sync>> {
+        let retry_policy = RetryPolicy::default().with_max_attempts(1);
+        download_retrying(url, file, partial, segments, retry_policy).await
+    }
+
+    /// Like `download`, but retrying under `retry_policy`.
+    async fn download_retrying(
+        url: &str,
+        file: &Path,
+        partial: &[u8],
+        segments: usize,
+        retry_policy: RetryPolicy,
+    ) -> Result<(PathBuf, u64), Box<dyn std::error::Error + Send + Sync>> {
+        let output = file.parent().unwrap().to_string_lossy().into_owned();
+        let config = DumpConfig::new_output_is_dir(&output, 1, url, 0, reqwest::Client::new())
+            .with_segments(segments)
+            .with_retry_policy(retry_policy);
+        let feed = Feed::new(Channel::default(), &config).await.unwrap();
+        std::fs::write(create_part_file_path(file), partial).unwrap();
+
+        feed.download_and_store_item(
+            &Enclosure::new(url, "audio/mpeg", None),
+            file.to_path_buf(),
+            Arc::new(MultiProgress::with_draw_target(ProgressDrawTarget::hidden())),
+            String::from("episode"),
+            &CancellationToken::new(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn resume_partial_file() {
+        let episode: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let dir = TempDir::new("feed-resume");
+        let file = dir.path().join("episode.mp3");
+
+        // Partial responses are appended to what is already there
+        let served = episode.clone();
+        let (url, requests) = serve(move |request| serve_ranges(&served, request)).await;
+        let (stored, size) = download(&url, &file, &episode[..400], 1).await.unwrap();
+        assert_eq!((stored.as_path(), size), (file.as_path(), 1000));
+        assert_eq!(std::fs::read(&file).unwrap(), episode);
+        assert_eq!(
+            requests.lock().unwrap().last(),
+            Some(&Request {
+                method: String::from("GET"),
+                range_start: Some(400),
+                range_end: Some(999),
+                ..Request::default()
+            })
+        );
+        assert!(!create_part_file_path(&file).exists());
+
+        // Full responses replace it
+        let served = episode.clone();
+        let (url, _) = serve(move |request| match request.method.as_str() {
+            "GET" => response(request, "200 OK", &[], &served),
+            _ => serve_ranges(&served, request),
+        })
+        .await;
+        download(&url, &file, b"not the episode", 1).await.unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), episode);
+
+        // Partial responses that start elsewhere are rejected, keeping the partial file
+        let served = episode.clone();
+        let (url, _) = serve(move |request| {
+            serve_ranges(
+                &served,
+                &Request {
+                    range_start: request.range_start.map(|_| 0),
+                    ..request.clone()
+                },
+            )
+        })
+        .await;
+        std::fs::remove_file(&file).unwrap();
+        assert!(download(&url, &file, &episode[..400], 1).await.is_err());
+        assert_eq!(
+            std::fs::read(create_part_file_path(&file)).unwrap(),
+            &episode[..400]
+        );
+        assert!(!file.exists());
+    }
+
+    #[tokio::test]
+    async fn resume_without_head() {
+        let episode: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let dir = TempDir::new("feed-no-head");
+        let file = dir.path().join("episode.mp3");
+
+        // The rest of the file is still asked for, and appended when it's what comes back
+        let served = episode.clone();
+        let (url, requests) = serve(move |request| match request.method.as_str() {
+            "HEAD" => response(request, "405 Method Not Allowed", &[], &[]),
+            _ => serve_ranges(&served, request),
+        })
+        .await;
+        download(&url, &file, &episode[..400], 1).await.unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), episode);
+        assert_eq!(
+            requests.lock().unwrap().last(),
+            Some(&Request {
+                method: String::from("GET"),
+                range_start: Some(400),
+                ..Request::default()
+            })
+        );
+
+        // Or replaced when the whole file comes back
+        let served = episode.clone();
+        let (url, _) = serve(move |request| match request.method.as_str() {
+            "HEAD" => response(request, "405 Method Not Allowed", &[], &[]),
+            _ => response(request, "200 OK", &[], &served),
+        })
+        .await;
+        download(&url, &file, b"not the episode", 1).await.unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), episode);
+    }
+
+    #[tokio::test]
+    async fn resume_segments() {
+        let chunk = CHUNK_SIZE as usize;
+        let episode: Vec<u8> = (0..=250).cycle().take(3 * chunk + 1000).collect();
+        let dir = TempDir::new("feed-segments");
+        let file = dir.path().join("episode.mp3");
+        let part_file = create_part_file_path(&file);
+        let segments_file = create_segments_file_path(&part_file);
+        let get_ranges = |requests: &Mutex<Vec<Request>>| {
+            let mut ranges: Vec<(Option<u64>, Option<u64>)> = requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|request| request.method == "GET")
+                .map(|request| (request.range_start, request.range_end))
+                .collect();
+            ranges.sort_unstable();
+            ranges
+        };
+
+        // A run that completed the first and third chunks only fetches the missing ones
+        let mut partial = episode.clone();
+        partial[chunk..2 * chunk].fill(0);
+        partial.truncate(3 * chunk);
+        std::fs::write(
+            &segments_file,
+            format!("0-{}\n{}-{}\n", chunk, 2 * chunk, 3 * chunk),
+        )
+        .unwrap();
+
+        let served = episode.clone();
+        let (url, requests) = serve(move |request| serve_ranges(&served, request)).await;
+        download(&url, &file, &partial, 2).await.unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), episode);
+        assert!(!segments_file.exists());
+
+        let chunk = chunk as u64;
+        assert_eq!(
+            get_ranges(&requests),
+            [
+                (Some(chunk), Some(2 * chunk - 1)),
+                (Some(3 * chunk), Some(3 * chunk + 999))
+            ]
+        );
+
+        // When a chunk fails for good, the chunks that completed are kept for the next run
+        let served = episode.clone();
+        let (url, _) = serve_slowly(
+            move |request| {
+                if request.range_start == Some(chunk) {
+                    Duration::from_millis(300)
+                } else {
+                    Duration::ZERO
+                }
+            },
+            move |request| match request.range_start {
+                Some(start) if start == chunk => response(request, "404 Not Found", &[], &[]),
+                _ => serve_ranges(&served, request),
+            },
+        )
+        .await;
+        std::fs::remove_file(&file).unwrap();
+        let completed = &episode[..chunk as usize];
+        assert!(download(&url, &file, completed, 2).await.is_err());
+        let partial = std::fs::read(&part_file).unwrap();
+        assert_eq!(partial.len(), episode.len());
+        let (chunk, length) = (chunk as usize, episode.len());
+        assert_eq!(&partial[..chunk], &episode[..chunk]);
+        assert_eq!(&partial[2 * chunk..], &episode[2 * chunk..]);
+        assert_eq!(
+            std::fs::read_to_string(&segments_file).unwrap(),
+            format!("0-{}\n{}-{}\n", chunk, 2 * chunk, length)
+        );
+
+        // And the next run only fetches the chunk that failed
+        let served = episode.clone();
+        let (url, requests) = serve(move |request| serve_ranges(&served, request)).await;
+        download(&url, &file, &partial, 2).await.unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), episode);
+        assert!(!segments_file.exists());
+        let chunk = chunk as u64;
+        assert_eq!(get_ranges(&requests), [(Some(chunk), Some(2 * chunk - 1))]);
+    }
+
+    #[tokio::test]
+    async fn retry_failed_segment() {
+        let chunk = CHUNK_SIZE as u64;
+        let episode: Vec<u8> = (0..=250).cycle().take(3 * chunk as usize).collect();
+        let dir = TempDir::new("feed-segment-retry");
+        let file = dir.path().join("episode.mp3");
+
+        // The first response for the second chunk is cut short
+        let served = episode.clone();
+        let cut = AtomicBool::new(false);
+        let (url, requests) = serve(move |request| {
+            let mut served = serve_ranges(&served, request);
+            if request.range_start == Some(chunk) && !cut.swap(true, Ordering::SeqCst) {
+                served.truncate(served.len() - 1000);
+            }
+            served
+        })
+        .await;
+
+        let retry_policy = RetryPolicy::default()
+            .with_max_attempts(3)
+            .with_base_delay(Duration::from_millis(10));
+        download_retrying(&url, &file, &[], 2, retry_policy)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), episode);
+
+        // Only the bytes that didn't arrive are fetched again
+        let retried: Vec<(Option<u64>, Option<u64>)> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| {
+                request
+                    .range_start
+                    .map_or(false, |start| start > chunk && start < 2 * chunk)
+            })
+            .map(|request| (request.range_start, request.range_end))
+            .collect();
+        assert_eq!(retried, [(Some(2 * chunk - 1000), Some(2 * chunk - 1))]);
+    }
+
+    #[tokio::test]
+    async fn host_downloads_share_slots() {
+        let dir = TempDir::new("feed-hosts");
+        let (url, requests) = serve_slowly(
+            |_| Duration::from_millis(300),
+            |request| response(request, "200 OK", &[], b"episode"),
+        )
+        .await;
+        let feed_xml = format!(
+            r#"<rss version="2.0"><channel><title>Hosts</title><link>http://example.com</link>
+            <description>Hosts</description>
+            <item><title>One</title><enclosure url="{0}?1" type="audio/mpeg" length="7"/></item>
+            <item><title>Two</title><enclosure url="{0}?2" type="audio/mpeg" length="7"/></item>
+            </channel></rss>"#,
+            url
+        );
+
+        let client = reqwest::Client::new();
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        let configs = [&first, &second].map(|output| {
+            DumpConfig::new_output_is_dir(&output.to_string_lossy(), 4, &url, 0, client.clone())
+        });
+        let mut feeds = vec![];
+        for config in &configs {
+            let channel = Channel::read_from(feed_xml.as_bytes()).unwrap();
+            feeds.push(Feed::new(channel, config).await.unwrap());
+        }
+        let lists: Vec<Vec<Weak<Item>>> = feeds
+            .iter()
+            .map(|feed| feed.full_download_list.iter().map(Arc::downgrade).collect())
+            .collect();
+        let jobs: Vec<(&Feed, &[Weak<Item>])> = feeds
+            .iter()
+            .zip(&lists)
+            .map(|(feed, list)| (feed, list.as_slice()))
+            .collect();
+
+        // Four downloads from one host, at most two at once, take at least two rounds
+        let start = std::time::Instant::now();
+        let failed = download_feeds(&jobs, 4, Some(2), &CancellationToken::new()).await;
+        assert!(failed.iter().all(Vec::is_empty));
+        assert!(start.elapsed() >= Duration::from_millis(600));
+        assert_eq!(
+            requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|request| request.method == "GET")
+                .count(),
+            4
+        );
+        assert_eq!(std::fs::read(first.join("One.mp3")).unwrap(), b"episode");
+    }
+
+    #[tokio::test]
+    async fn revalidate_cached_feed() {
+        let dir = TempDir::new("feed-cache");
+        let cache = FeedCache::new(dir.path().to_path_buf());
+        let last_modified = "Sat, 01 Jan 2022 00:00:00 GMT";
+        let feed_xml = r#"<rss version="2.0"><channel><title>Cached</title>
+            <link>http://example.com</link><description>Cached</description></channel></rss>"#;
+
+        let (url, requests) = serve(move |request| match request.if_none_match.as_deref() {
+            Some("\"v1\"") => response(request, "304 Not Modified", &[], &[]),
+            _ => response(
+                request,
+                "200 OK",
+                &[
+                    String::from("ETag: \"v1\""),
+                    format!("Last-Modified: {}", last_modified),
+                ],
+                feed_xml.as_bytes(),
+            ),
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let retry_policy = RetryPolicy::default().with_max_attempts(1);
+        let headers = HeaderMap::new();
+        let fetch = || fetch_url(&url, &client, &headers, &retry_policy, &cache);
+
+        // The feed is cached along with its validators
+        let fetched = fetch().await.unwrap();
+        assert_eq!(fetched.not_modified_since, None);
+        let (validators, body) = cache.load(&url, &headers).unwrap();
+        assert_eq!(
+            validators,
+            CacheValidators {
+                url: url.clone(),
+                etag: Some(String::from("\"v1\"")),
+                last_modified: Some(last_modified.to_owned()),
+                stored_at: validators.stored_at,
+            }
+        );
+        assert!(validators.stored_at.is_some());
+        assert_eq!(body, feed_xml.as_bytes());
+
+        // Which are sent back, and the cached feed is used when it didn't change
+        let revalidated = fetch().await.unwrap();
+        assert_eq!(revalidated.not_modified_since, validators.stored_at);
+        assert_eq!(revalidated.channel, fetched.channel);
+        let request = requests.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(request.if_none_match.as_deref(), Some("\"v1\""));
+        assert_eq!(request.if_modified_since.as_deref(), Some(last_modified));
+
+        // Once a run downloaded everything from the cached feed there is nothing new in it
+        let output = TempDir::new("feed-cache-output");
+        let config = DumpConfig::new_output_is_dir(
+            &output.path().to_string_lossy(),
+            1,
+            &url,
+            0,
+            client.clone(),
+        );
+        let feed = Feed::new(revalidated.channel, &config).await.unwrap();
+        assert!(!feed.unchanged_since_last_run(revalidated.not_modified_since));
+        feed.record_run().unwrap();
+        assert!(feed.unchanged_since_last_run(revalidated.not_modified_since));
+        assert!(!feed.unchanged_since_last_run(None));
+    }
+
+    #[tokio::test]
+    async fn skip_items_without_enclosure() {
+        let (url, requests) = serve(|request| response(request, "200 OK", &[], b"episode")).await;
+        let atom = format!(
+            r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>Atom</title><id>urn:atom</id>
+            <updated>2022-01-01T00:00:00Z</updated>
+            <entry><title>Post</title><id>urn:post</id><updated>2022-01-01T00:00:00Z</updated>
+            <link href="http://example.com/post"/></entry>
+            <entry><title>Episode</title><id>urn:episode</id><updated>2022-01-01T00:00:00Z</updated>
+            <link rel="enclosure" href="{}?atom" type="audio/mpeg" length="7"/></entry>
+            </feed>"#,
+            url
+        );
+        let json = format!(
+            r#"{{"version": "https://jsonfeed.org/version/1.1", "title": "JSON", "items": [
+            {{"id": "post", "title": "Post", "content_text": "Post"}},
+            {{"id": "episode", "title": "Episode", "attachments": [
+                {{"url": "{}?json", "mime_type": "audio/mpeg", "size_in_bytes": 7}}]}}]}}"#,
+            url
+        );
+
+        for document in &[atom, json] {
+            let dir = TempDir::new("feed-no-enclosure");
+            let config = DumpConfig::new_output_is_dir(
+                &dir.path().to_string_lossy(),
+                1,
+                &url,
+                0,
+                reqwest::Client::new(),
+            );
+            let channel = Channel::read_from(document.as_bytes()).unwrap();
+            let mut feed = Feed::new(channel, &config).await.unwrap();
+            let everything: QueryOp = Box::new(|_: (&Item, usize, &Feed)| true);
+
+            // The post has nothing to download
+            let download_list = feed.build_download_list(&everything).unwrap();
+            assert_eq!(download_list.len(), 1);
+            assert_eq!(download_list[0].upgrade().unwrap().title(), Some("Episode"));
+
+            // And is left out by downloads given it anyway
+            let full_list = feed.build_list_from_query(&everything).unwrap();
+            assert_eq!(full_list.len(), 2);
+            let failed_downs = feed
+                .download_items(&full_list, &CancellationToken::new())
+                .await;
+            assert!(failed_downs.is_empty());
+            assert!(dir.path().join("Episode.mp3").exists());
+        }
+        let gets = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.method == "GET")
+            .count();
+        assert_eq!(gets, 2);
+    }
+
+    #[tokio::test]
+    async fn select_since_last_run() {
+        let dir = TempDir::new("feed-last-run");
+        let config = DumpConfig::new_output_is_dir(
+            &dir.path().to_string_lossy(),
+            1,
+            "feed",
+            0,
+            reqwest::Client::new(),
+        );
+        let feed_xml = r#"<rss version="2.0"><channel><title>Runs</title>
+            <link>http://example.com</link><description>Runs</description>
+            <item><title>Future</title><pubDate>Fri, 01 Jan 2100 00:00:00 GMT</pubDate></item>
+            <item><title>Past</title><pubDate>Sat, 01 Jan 2022 00:00:00 GMT</pubDate></item>
+            </channel></rss>"#;
+        let since_last_run = || {
+            crate::query::expr::QueryExpr::new("date:since-last-run")
+                .unwrap()
+                .build_query_op()
+        };
+        let titles = |feed: &mut Feed, query: &QueryOp| -> Vec<String> {
+            feed.build_list_from_query(query)
+                .unwrap()
+                .iter()
+                .map(|item| item.upgrade().unwrap().title().unwrap().to_owned())
+                .collect()
+        };
+
+        // Until a run downloads everything it selects, every item is selected
+        let channel = Channel::read_from(feed_xml.as_bytes()).unwrap();
+        let mut feed = Feed::new(channel, &config).await.unwrap();
+        assert_eq!(feed.last_run(), None);
+        assert_eq!(titles(&mut feed, &since_last_run()), ["Future", "Past"]);
+
+        // And after one, only the items published since it fetched the feed
+        feed.record_run().unwrap();
+        let channel = Channel::read_from(feed_xml.as_bytes()).unwrap();
+        let mut feed = Feed::new(channel, &config).await.unwrap();
+        assert!(feed.last_run().is_some());
+        assert_eq!(titles(&mut feed, &since_last_run()), ["Future"]);
+    }
+}
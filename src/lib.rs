@@ -1,9 +1,23 @@
 #[macro_use]
 extern crate log;
 
+pub mod bandwidth;
+pub mod cache;
 pub mod config;
 pub mod error;
 pub mod ext;
 pub mod feed;
+pub mod lock;
+pub mod model;
 pub mod query;
+pub mod retry;
+pub mod sanitize;
+pub mod space;
+pub mod state;
+pub mod subscriptions;
+pub mod template;
+#[cfg(test)]
+mod test_utils;
 pub mod utils;
+pub mod verify;
+pub mod watch;
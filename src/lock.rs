@@ -0,0 +1,67 @@
+use super::error::RssDumpError;
+
+use fs2::FileExt;
+
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+/// Name of the file, inside the output directory, locked while downloading into it.
+pub const LOCK_FILE_NAME: &str = ".dumptruckrss.lock";
+
+/// Exclusive lock over an output directory, so two runs never download into the same
+/// directory at once. The lock is released when this is dropped, or when the process dies.
+#[derive(Debug)]
+pub struct OutputLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl OutputLock {
+    pub fn acquire(output_dir: &Path) -> Result<Self, Box<RssDumpError>> {
+        let path = output_dir.join(LOCK_FILE_NAME);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+
+        if file.try_lock_exclusive().is_err() {
+            return Err(Box::new(RssDumpError::OutputDirIsLocked(
+                output_dir.to_path_buf(),
+            )));
+        }
+        info!("Locked {}", path.display());
+
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        if FileExt::unlock(&self.file).is_ok() {
+            info!("Unlocked {}", self.path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn lock_output_dir() {
+        let dir = TempDir::new("lock");
+
+        let lock = OutputLock::acquire(dir.path()).unwrap();
+        assert!(matches!(
+            *OutputLock::acquire(dir.path()).unwrap_err(),
+            RssDumpError::OutputDirIsLocked(_)
+        ));
+
+        // Dropping the lock releases it
+        drop(lock);
+        let lock = OutputLock::acquire(dir.path()).unwrap();
+        drop(lock);
+    }
+}
@@ -5,21 +5,37 @@
 #[macro_use]
 extern crate log;
 
-use chrono::prelude::*;
+use chrono::DateTime;
 use clap::{
-    crate_authors, crate_description, crate_name, crate_version, App, Arg, ArgGroup, SubCommand,
+    crate_authors, crate_description, crate_name, crate_version, App, Arg, ArgGroup, ArgMatches,
+    ErrorKind, SubCommand,
 };
-use rss::{Channel, ChannelBuilder};
+use reqwest::header::HeaderMap;
 use tokio::fs::{self, File};
 use tokio::io as tokio_io;
+use tokio_util::sync::CancellationToken;
 
-use dumptruckrss::config::DumpConfig;
+use dumptruckrss::bandwidth::parse_host_rate;
+use dumptruckrss::config::{ConfigFile, DumpConfig, Profile};
 use dumptruckrss::error::RssDumpError;
-use dumptruckrss::feed::Feed;
-use dumptruckrss::query::{Query, QueryOp, RANGE_DELIMITER};
+use dumptruckrss::feed::{download_feeds, fetch_channel, Feed};
+use dumptruckrss::lock::OutputLock;
+use dumptruckrss::model::Item;
+use dumptruckrss::query::expr::QueryExpr;
+use dumptruckrss::query::{QueryOp, RANGE_DELIMITER};
+use dumptruckrss::retry::RetryPolicy;
+use dumptruckrss::space::check_free_space;
+use dumptruckrss::subscriptions::Subscriptions;
+use dumptruckrss::utils::{cancel_on_signal, create_part_file_path, http_client};
+use dumptruckrss::verify::verify_feed;
+use dumptruckrss::watch::{watch, WatchedFeed};
 
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Weak;
+
+/// Passes over the downloads that failed, each resuming them from their partial file. Every
+/// request within a pass is already retried by the retry policy.
+const DOWNLOAD_PASSES: u32 = 3;
 
 #[allow(clippy::too_many_lines)]
 #[tokio::main]
@@ -33,7 +49,7 @@ async fn main() -> Result<(), Box<RssDumpError>> {
                 .short("u")
                 .long("url")
                 .value_name("URL")
-                .help("RSS feed url")
+                .help("RSS, Atom or JSON feed url")
                 .takes_value(true),
         )
         .arg(
@@ -41,15 +57,111 @@ async fn main() -> Result<(), Box<RssDumpError>> {
                 .short("f")
                 .long("file")
                 .value_name("FILE")
-                .help("RSS feed File")
+                .help("RSS, Atom or JSON feed File")
+                .takes_value(true),
+        )
+        .group(ArgGroup::with_name("input").args(&["url", "file"]))
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Configuration file [default: ~/.config/dumptruckrss/config.toml]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .short("p")
+                .long("profile")
+                .value_name("NAME")
+                .help("Use the settings of this profile from the configuration file. \
+                       Arguments given on the command line take precedence")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("template")
+                .short("n")
+                .long("template")
+                .value_name("TEMPLATE")
+                .help("Name downloaded files after TEMPLATE, e.g. \
+                       '{pub_date:%Y-%m-%d} - {number:04} - {title}.{ext}'. Fields: title, guid, \
+                       pub_date, number, season, episode, episode_type, explicit, author, feed_title \
+                       and ext. \
+                       A '/' creates a subdirectory [default: '{title}.{ext}']")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sanitize")
+                .long("sanitize")
+                .value_name("POLICY")
+                .help("Make downloaded file names valid in every filesystem (portable), \
+                       in FAT and NTFS (windows) or only in POSIX filesystems (posix) \
+                       [default: portable]")
+                .possible_values(&["portable", "windows", "posix"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .value_name("ATTEMPTS")
+                .help("Attempts made for every request before giving up [default: 10]")
                 .takes_value(true),
         )
-        .group(
-            ArgGroup::with_name("input")
-                .args(&["url", "file"])
-                .required(true),
+        .arg(
+            Arg::with_name("retry-delay")
+                .long("retry-delay")
+                .value_name("DELAY")
+                .help("Delay before the first retry, doubled on every further retry [default: 1s]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retry-max-delay")
+                .long("retry-max-delay")
+                .value_name("DELAY")
+                .help("Longest delay between retries, also for servers asking to retry later \
+                       [default: 1m]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("limit-rate")
+                .long("limit-rate")
+                .value_name("RATE")
+                .help("Bytes per second all downloads may use together, e.g. 500K or 2M")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("limit-rate-host")
+                .long("limit-rate-host")
+                .value_name("HOST=RATE")
+                .help("Bytes per second the downloads from HOST may use together, \
+                       e.g. cdn.example.com=1M. Can be given several times")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("space-policy")
+                .long("space-policy")
+                .value_name("POLICY")
+                .help("What to do when the downloads don't fit in the free space: fail before \
+                       downloading, skip the items that don't fit (trim) or ignore it \
+                       [default: fail]")
+                .possible_values(&["fail", "trim", "ignore"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("space-reserve")
+                .long("space-reserve")
+                .value_name("SIZE")
+                .help("Bytes to keep free after downloading, e.g. 500M or 2G [default: 100M]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("subscriptions")
+                .long("subscriptions")
+                .value_name("FILE")
+                .help("Subscriptions file [default: ~/.config/dumptruckrss/subscriptions.json]")
+                .takes_value(true),
         )
-        // TODO: add support for multiple queries
         .arg(
             Arg::with_name("query")
                 .short("q")
@@ -57,20 +169,47 @@ async fn main() -> Result<(), Box<RssDumpError>> {
                 .value_name("QUERY")
                 .help(
                     &format!("Query items with the following patterns: \n\
-                        [date | title | description | number | notexists | latest]\n\n\
+                        [date | title | description | author | category | guid | link | enclosure.url | \
+                        enclosure.type | comments | number | season | episode | type | explicit | size | duration | notexists | new | latest]\n\
+                        Patterns can be combined with AND, OR, NOT and grouped with parentheses.\n\n\
                         Examples:\n\t\
+                        Date: Select items published on the dates, which may be relative to today \n\t\t\
+                        'date:[2023-01-01{RANGE_DELIMITER}today]', 'date:last-30d', 'date:this-year' or \
+                        'date:since-last-run'\n\t\
                         Number: Select items in the feed with the following numbers. \n\t\t\
-                        'number:[0{}12]' (range), 'number:20' (scalar), 'number:{{[0{}12], 20}}' (set)\n\t\
+                        'number:[0{RANGE_DELIMITER}12]' (range), 'number:20' (scalar), 'number:{{[0{RANGE_DELIMITER}12], 20}}' (set)\n\t\
                         Title: Select items which contain the keyword \
                         \n\t\t'title:my_title' (value) or 'title:{{my_title, other_title}}' (set)\n\t\
                         Description: Select items where their description contain the keyword(s) \n\t\t\
                         'description:my_word' (value) or 'description:{{my_word, other_word}}' \
                         (set)\n\t\
+                        Season and Episode: Select items by the itunes:season and itunes:episode \
+                        numbers the feed gives them, or podcast:season and podcast:episode \n\t\t\
+                        'episode:[1{RANGE_DELIMITER}20]' or 'season:3'\n\t\
+                        Type: Select items by itunes:episodeType, full, trailer or bonus \n\t\t\
+                        'type:full'\n\t\
+                        Explicit: Select items flagged as explicit or, with 'explicit:no', as clean \n\t\t\
+                        'explicit' or 'explicit:no'\n\t\
+                        Size: Select items whose enclosure length is in the range or set, with units \
+                        K, M, G or T\n\t\t\
+                        'size:[100MB{RANGE_DELIMITER}2GB]' or 'size:{{[0{RANGE_DELIMITER}1M], 5G}}'\n\t\
+                        Duration: Select items whose itunes:duration is in the range or set, as \
+                        seconds, HH:MM:SS or '1h 30m'\n\t\t\
+                        'duration:[00:30:00{RANGE_DELIMITER}01:30:00]'\n\t\
+                        Item fields: Select items whose author, category, guid, link, enclosure.url, \
+                        enclosure.type or comments contain the keyword(s), like titles \n\t\t\
+                        'category:Bonus' or 'enclosure.url:{{cdn.example.com, mirror.example.com}}'\n\t\
+                        Text modifiers: Match text fields regardless of case with 'ci:', \
+                        whole words with 'word:', or a regular expression with '~/pattern/flags' \n\t\t\
+                        'title:ci:word:cheese' or 'title~/^Episode \\d+:/i'\n\t\
                         Not Exists: Select items which are not present in the specified directory \n\t\t\
                         'notexists'\n\t\
+                        New: Select items which were never downloaded to the specified directory \n\t\t\
+                        'new'\n\t\
                         Latest: Select the latest item in the feed\n\t\t\
-                        'latest' downloads the most recent item or 'latest:N' to download the N most recent items",
-                        RANGE_DELIMITER, RANGE_DELIMITER),
+                        'latest' downloads the most recent item or 'latest:N' to download the N most recent items\n\t\
+                        Expression: Combine patterns with boolean operators \n\t\t\
+                        'title:interview AND NOT (number:0 OR description:rerun)'"),
                 )
                 .default_value("notexists")
                 .takes_value(true),
@@ -78,37 +217,53 @@ async fn main() -> Result<(), Box<RssDumpError>> {
         .subcommand(
             SubCommand::with_name("download")
                 .about("Download queried items in this feed to the specified folder")
+                .args(&download_args("Download all subscriptions, each into its own output location")),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Keep polling the feed and download new items matching the query")
+                .args(&download_args("Watch all subscriptions, each with its own interval"))
                 .arg(
-                    Arg::with_name("output")
-                        .short("o")
-                        .long("output")
-                        .value_name("OUTPUT")
-                        .help("Output location to download contents")
-                        .takes_value(true)
-                        .required(true),
-                )
-                .arg(
-                    Arg::with_name("ndownloads")
-                        .short("d")
-                        .long("ndownloads")
-                        .value_name("NDOWNLOADS")
-                        .help("Maximum number of concurrent downloads")
-                        .default_value("1")
+                    Arg::with_name("interval")
+                        .short("i")
+                        .long("interval")
+                        .value_name("INTERVAL")
+                        .help("Time between polls, e.g. '30m' or '1h 30m'")
+                        .default_value("1h")
                         .takes_value(true),
                 )
                 .arg(
-                    Arg::with_name("timeout")
-                        .short("t")
-                        .long("timeout")
-                        .value_name("TIMEOUT")
-                        .help("Timeout between failures in ms")
-                        .default_value("300")
+                    Arg::with_name("jitter")
+                        .long("jitter")
+                        .value_name("JITTER")
+                        .help("Delay each poll by a random time up to JITTER, e.g. '5m'")
+                        .default_value("0s")
                         .takes_value(true),
-                )
+                ),
         )
         .subcommand(
             SubCommand::with_name("check")
-                .about("Check query results"),
+                .about("Check query results")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("OUTPUT")
+                        .help("Output location the queries are checked against [default: .]")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check downloaded files against the feed and the recorded hashes")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("OUTPUT")
+                        .help("Output location to verify [default: .]")
+                        .takes_value(true),
+                ),
         )
         .subcommand(
             SubCommand::with_name("create")
@@ -130,226 +285,779 @@ async fn main() -> Result<(), Box<RssDumpError>> {
                         .help("Name of the feed to be created")
                         .takes_value(true)
                 )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Format of the feed to be created")
+                        .possible_values(&["rss", "json"])
+                        .default_value("rss")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("subscriptions")
+                .about("Manage the feeds downloaded by 'download --all'")
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("Subscribe to every feed in an OPML file, using QUERY as their default query")
+                        .arg(
+                            Arg::with_name("opml")
+                                .value_name("OPML")
+                                .help("OPML file to import")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("output")
+                                .short("o")
+                                .long("output")
+                                .value_name("OUTPUT")
+                                .help("Base location, each feed is downloaded into a folder named after it")
+                                .takes_value(true)
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("Export the subscriptions to an OPML file")
+                        .arg(
+                            Arg::with_name("output")
+                                .short("o")
+                                .long("output")
+                                .value_name("OUTPUT")
+                                .help("OPML file to create")
+                                .takes_value(true)
+                                .required(true),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("List the subscriptions")),
         )
         .get_matches();
 
     env_logger::init();
 
-    // Get RSS feed from a url or a file
-    let rss_feed = if let Some(url) = matches.value_of("url") {
-        url
-    } else if let Some(file) = matches.value_of("file") {
-        file
-    } else {
-        unreachable!();
-    };
+    let subscriptions_path = matches
+        .value_of("subscriptions")
+        .map_or_else(Subscriptions::default_path, PathBuf::from);
 
-    // Access feed
-    let channel = if matches.value_of("url").is_some() {
-        let content = reqwest::get(rss_feed).await?.bytes().await?;
-        Channel::read_from(&content[..])?
+    // Settings from the configuration file, which the command line overrides
+    let config_file = ConfigFile::load(
+        &matches
+            .value_of("config")
+            .map_or_else(ConfigFile::default_path, PathBuf::from),
+    )?;
+    let mut profile = config_file.profile(matches.value_of("profile"))?;
+    if let Some(template) = matches.value_of("template") {
+        profile.filename_template = Some(template.to_owned());
+    }
+    if let Some(sanitize) = matches.value_of("sanitize") {
+        profile.sanitize = Some(sanitize.to_owned());
+    }
+    if let Some(retries) = matches.value_of("retries") {
+        profile.retries = Some(retries.parse()?);
+    }
+    if let Some(retry_delay) = matches.value_of("retry-delay") {
+        profile.retry_delay = Some(retry_delay.to_owned());
+    }
+    if let Some(retry_max_delay) = matches.value_of("retry-max-delay") {
+        profile.retry_max_delay = Some(retry_max_delay.to_owned());
+    }
+    if let Some(space_policy) = matches.value_of("space-policy") {
+        profile.space_policy = Some(space_policy.to_owned());
+    }
+    if let Some(space_reserve) = matches.value_of("space-reserve") {
+        profile.space_reserve = Some(space_reserve.to_owned());
+    }
+    if let Some(limit_rate) = matches.value_of("limit-rate") {
+        profile.limit_rate = Some(limit_rate.to_owned());
+    }
+    for host_rate in matches.values_of("limit-rate-host").into_iter().flatten() {
+        let (host, rate) = parse_host_rate(host_rate)?;
+        profile.host_limit_rates.insert(host, rate.to_string());
+    }
+    let retry_policy = profile.parse_retry_policy()?;
+    let headers = profile.header_map()?;
+    let client = http_client()?;
+    // Downloads stop cleanly on a signal, the other subcommands are left to be ended by it
+    let cancel = if matches.subcommand_matches("download").is_some()
+        || matches.subcommand_matches("watch").is_some()
+    {
+        cancel_on_signal()?
     } else {
-        let file = std::fs::File::open(rss_feed)?;
-        Channel::read_from(BufReader::new(file))?
+        CancellationToken::new()
     };
 
-    let query_ops: Vec<QueryOp> = if let Some(query_str) = matches.value_of("query") {
-        let query = Query::new(query_str)?;
-        let queries = vec![query];
-        queries
-            .into_iter()
-            .map(dumptruckrss::query::Query::build_query_op)
-            .collect()
-    } else {
-        unreachable!();
-    };
+    let query_str = resolve(&matches, "query", profile.query.as_deref()).unwrap();
 
-    // Download Subcommand
-    if let Some(matches) = matches.subcommand_matches("download") {
-        let n_downloads: usize = if let Some(n_downloads) = matches.value_of("ndownloads") {
-            info!("Downloading {} items concurrently", n_downloads);
-            n_downloads.parse()?
-        } else {
-            unreachable!();
-        };
+    // Subscriptions Subcommand
+    if let Some(subscriptions_matches) = matches.subcommand_matches("subscriptions") {
+        return manage_subscriptions(subscriptions_matches, &subscriptions_path, query_str).await;
+    }
 
-        let timeout: usize = if let Some(timeout) = matches.value_of("timeout") {
-            timeout.parse()?
-        } else {
-            unreachable!();
-        };
+    // Only override the query of each subscription when one is given explicitly
+    let subscriptions_query = Some(query_str).filter(|_| matches.occurrences_of("query") > 0);
 
-        let config = DumpConfig::new_output_is_dir(
-            matches.value_of("output").unwrap(),
-            n_downloads,
-            rss_feed,
-            timeout,
-        );
-        let mut feed = Feed::new(channel, &config).await;
+    if let Some(download_matches) = matches.subcommand_matches("download") {
+        if download_matches.is_present("all") {
+            return download_subscriptions(
+                download_matches,
+                &profile,
+                &headers,
+                &client,
+                &cancel,
+                &subscriptions_path,
+                subscriptions_query,
+            )
+            .await;
+        }
+    }
 
-        // Create directory if necessary
-        config.create_output_dir().await?;
+    if let Some(watch_matches) = matches.subcommand_matches("watch") {
+        if watch_matches.is_present("all") {
+            return watch_subscriptions(
+                watch_matches,
+                &profile,
+                &headers,
+                &client,
+                &cancel,
+                &subscriptions_path,
+                subscriptions_query,
+            )
+            .await;
+        }
+    }
 
-        println!(
-            "You are about to download the contents of the feed: {}",
-            feed.title()
-        );
+    // Get RSS feed from a url or a file, given either in the command line or the profile
+    let (rss_feed, is_url) = match (matches.value_of("url"), matches.value_of("file")) {
+        (Some(url), _) => (url, true),
+        (None, Some(file)) => (file, false),
+        (None, None) => match (profile.url.as_deref(), profile.file.as_deref()) {
+            (Some(url), _) => (url, true),
+            (None, Some(file)) => (file, false),
+            (None, None) => missing_argument("One of --url, --file or --profile is required"),
+        },
+    };
 
-        info!("{} contains {} items", feed.title(), feed.total_items(),);
+    // Watch Subcommand, which fetches the feed on every poll
+    if let Some(watch_matches) = matches.subcommand_matches("watch") {
+        let config = download_config(watch_matches, &profile, headers, &client, rss_feed)?;
+        let interval = resolve(watch_matches, "interval", profile.interval.as_deref()).unwrap();
+        let mut feeds = [WatchedFeed::new(
+            &config,
+            is_url,
+            query_str,
+            humantime::parse_duration(interval)?,
+        )?];
 
-        let is_write = config.is_output_dir_write()?;
-        if is_write {
-            info!(
-                "{} is writable by the current user",
-                config.get_output_display()
-            );
-        } else {
-            return Err(Box::new(RssDumpError::OutputDirIsNotWritable(
-                config.get_output().to_path_buf(),
-            )));
-        }
+        prepare_output_dir(&config).await?;
+        let _lock = OutputLock::acquire(config.get_output_dir())?;
 
-        let mut download_list = feed.build_list_from_query(&query_ops)?;
+        let jitter = humantime::parse_duration(watch_matches.value_of("jitter").unwrap())?;
+        return watch(&mut feeds, jitter, &cancel).await;
+    }
 
-        let mut loops = 0_usize;
-        let not_done;
+    // Access feed
+    let fetched = fetch_channel(rss_feed, is_url, &client, &headers, &retry_policy).await?;
+    let (channel, not_modified_since) = (fetched.channel, fetched.not_modified_since);
 
-        loop {
-            let failed_downs = feed.download_items(&download_list).await;
+    let query_op: QueryOp = QueryExpr::new(query_str)?.build_query_op();
 
-            let has_failed_downs = {
-                // Build new download list
-                let failed_items: Vec<&PathBuf> =
-                    failed_downs.iter().map(|(_, path, _)| path).collect();
-                if !failed_items.is_empty() {
-                    println!(
-                        "{} Downloads failed. Retrying with failed list",
-                        failed_items.len()
-                    );
-                }
+    // Download Subcommand
+    if let Some(matches) = matches.subcommand_matches("download") {
+        let config = download_config(matches, &profile, headers, &client, rss_feed)?;
+        let mut feed = Feed::new(channel, &config).await?;
 
-                // Delete failed downloads, if they exist
-                for item_to_delete in &failed_items {
-                    info!("Deleting {:?}", item_to_delete);
-                    fs::remove_file(item_to_delete).await?;
-                }
+        // Create directory if necessary
+        prepare_output_dir(&config).await?;
+        let _lock = OutputLock::acquire(config.get_output_dir())?;
 
-                failed_items.is_empty()
-            };
+        if feed.unchanged_since_last_run(not_modified_since) {
+            println!(
+                "{} didn't change since the last run. Nothing to download",
+                feed.title()
+            );
+            return Ok(());
+        }
 
-            download_list = failed_downs.into_iter().map(|(item, _, _)| item).collect();
-            loops += 1;
+        println!(
+            "You are about to download the contents of the feed: {}",
+            feed.title()
+        );
 
-            if has_failed_downs || loops >= 10 {
-                not_done = !has_failed_downs;
-                break;
-            }
-        }
+        info!("{} contains {} items", feed.title(), feed.total_items(),);
 
-        if not_done {
-            println!("Download failed");
-        } else {
+        let mut download_list = feed.build_download_list(&query_op)?;
+        check_free_space(&[&feed], std::slice::from_mut(&mut download_list)).await?;
+
+        let done = download_with_retries(
+            std::slice::from_ref(&feed),
+            vec![download_list],
+            config.get_n_downloads(),
+            config.get_host_downloads(),
+            config.get_retry_policy(),
+            &cancel,
+        )
+        .await?;
+
+        if done {
             println!("Full Download Successfully Completed");
+        } else {
+            println!("Download failed");
         }
     }
     // Check Subcommand
-    else if matches.subcommand_matches("check").is_some() {
-        let config =
-            DumpConfig::new_output_is_dir(matches.value_of("output").unwrap(), 0, rss_feed, 0);
-        let mut feed = Feed::new(channel, &config).await;
+    else if let Some(check_matches) = matches.subcommand_matches("check") {
+        let config = DumpConfig::new_output_is_dir(
+            resolve(check_matches, "output", profile.output.as_deref()).unwrap_or("."),
+            0,
+            rss_feed,
+            0,
+            client.clone(),
+        )
+        .with_filename_template(profile.parse_filename_template()?)
+        .with_sanitize_policy(profile.parse_sanitize_policy()?);
+        let mut feed = Feed::new(channel, &config).await?;
 
-        let download_list = feed.build_list_from_query(&query_ops)?;
+        let download_list = feed.build_list_from_query(&query_op)?;
 
         if download_list.is_empty() {
-            println!(
-                "Didn't find any matches with query: {}",
-                matches.value_of("query").unwrap()
-            );
+            println!("Didn't find any matches with query: {query_str}");
         } else {
             println!("The following files match the query:");
 
             for item in download_list {
                 let item_access = item.upgrade().unwrap();
+                // Feeds may leave any of these out
                 println!(
                     "\t{}\n\t\tURL: {}\n\t\tDate: {}",
-                    item_access.title().unwrap(),
-                    item_access.enclosure().unwrap().url(),
-                    item_access.pub_date().unwrap()
+                    item_access.title().unwrap_or("(untitled)"),
+                    item_access
+                        .enclosure()
+                        .map_or("(no enclosure)", |enclosure| enclosure.url()),
+                    item_access
+                        .pub_date()
+                        .map_or_else(|| "(unknown)".to_owned(), DateTime::to_rfc2822)
                 );
             }
 
             println!(
-                "\nTo download these files run:\n\tdumptruckrss -u {}{} download -o {}",
+                "\nTo download these files run:\n\tdumptruckrss -u {} -q '{}' download -o {}",
                 config.get_feed(),
-                if !query_ops.is_empty() && matches.value_of("query").is_some() {
-                    format!(" -q '{}'", matches.value_of("query").unwrap())
-                } else {
-                    "".to_string()
-                },
+                query_str,
                 config.get_output_display(),
             );
         }
     }
+    // Verify Subcommand
+    else if let Some(verify_matches) = matches.subcommand_matches("verify") {
+        let config = DumpConfig::new_output_is_dir(
+            resolve(verify_matches, "output", profile.output.as_deref()).unwrap_or("."),
+            0,
+            rss_feed,
+            0,
+            client.clone(),
+        )
+        .with_filename_template(profile.parse_filename_template()?)
+        .with_sanitize_policy(profile.parse_sanitize_policy()?);
+        let feed = Feed::new(channel, &config).await?;
+
+        let problems = verify_feed(&feed).await?;
+
+        if problems.is_empty() {
+            println!(
+                "Every downloaded file in {} is intact",
+                config.get_output_display()
+            );
+        } else {
+            println!("The following files are damaged:");
+
+            for (path, problem) in &problems {
+                println!("\t{}: {}", path.display(), problem);
+            }
+        }
+    }
     // create Subcommand
-    else if let Some(matches) = matches.subcommand_matches("create") {
-        let config =
-            DumpConfig::new_output_is_file(matches.value_of("output").unwrap(), 0, rss_feed, 0)?;
-        let mut feed = Feed::new(channel, &config).await;
+    else if let Some(create_matches) = matches.subcommand_matches("create") {
+        let config = DumpConfig::new_output_is_file(
+            create_matches.value_of("output").unwrap(),
+            0,
+            rss_feed,
+            0,
+            client.clone(),
+        )?;
+        let mut feed = Feed::new(channel, &config).await?;
 
         // Create directory if necessary
         config.create_output_dir().await?;
 
-        let download_list = feed.build_list_from_query(&query_ops)?;
+        let download_list = feed.build_list_from_query(&query_op)?;
 
-        let now = Local::now().to_rfc2822();
-
-        let title = if let Some(title) = matches.value_of("title") {
+        #[allow(clippy::option_if_let_else)]
+        let title = if let Some(title) = create_matches.value_of("title") {
             title.to_string()
         } else {
-            format!("{}-{}", feed.title(), matches.value_of("query").unwrap())
+            format!("{}-{}", feed.title(), query_str)
         };
 
-        let new_channel = ChannelBuilder::default()
-            .title(title)
-            .link(feed.link())
-            .description(feed.description())
-            .language(if let Some(l) = feed.language() {
-                l.to_owned()
-            } else {
-                format!("")
-            })
-            .copyright(if let Some(c) = feed.copyright() {
-                c.to_owned()
-            } else {
-                format!("")
-            })
-            .managing_editor(if let Some(me) = feed.managing_editor() {
-                me.to_owned()
-            } else {
-                format!("")
-            })
-            .pub_date(if let Some(p) = feed.pub_date() {
-                p.to_owned()
-            } else {
-                format!("")
-            })
-            .last_build_date(now)
-            .categories(feed.categories())
-            .generator(Some(crate_name!().to_owned()))
-            .items(
-                download_list
-                    .iter()
-                    .map(|item| (*item.upgrade().unwrap()).clone())
-                    .collect::<Vec<rss::Item>>(),
-            )
-            .build()?;
+        let new_channel = feed.create_channel(&title, &download_list);
+        let content = match create_matches.value_of("format") {
+            Some("json") => new_channel.to_json_feed().to_string(),
+            _ => new_channel.to_rss(crate_name!())?.to_string(),
+        };
 
         let mut file = File::create(config.get_output()).await?;
 
-        tokio_io::copy(&mut new_channel.to_string().as_ref(), &mut file).await?;
+        tokio_io::copy(&mut content.as_ref(), &mut file).await?;
     } else {
         unreachable!();
     }
 
     Ok(())
 }
+
+/// Value of the argument `name`, unless it wasn't given and the profile sets it.
+fn resolve<'a>(
+    matches: &'a ArgMatches<'_>,
+    name: &str,
+    profile_value: Option<&'a str>,
+) -> Option<&'a str> {
+    if matches.occurrences_of(name) > 0 {
+        matches.value_of(name)
+    } else {
+        profile_value.or_else(|| matches.value_of(name))
+    }
+}
+
+/// Arguments shared by the subcommands that download.
+fn download_args(all_help: &str) -> [Arg<'_, '_>; 6] {
+    [
+        Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .value_name("OUTPUT")
+            .help("Output location to download contents")
+            .takes_value(true),
+        Arg::with_name("all")
+            .long("all")
+            .help(all_help)
+            .conflicts_with("output"),
+        Arg::with_name("ndownloads")
+            .short("d")
+            .long("ndownloads")
+            .value_name("NDOWNLOADS")
+            .help("Maximum number of concurrent downloads")
+            .default_value("1")
+            .takes_value(true),
+        Arg::with_name("host-downloads")
+            .long("host-downloads")
+            .value_name("NDOWNLOADS")
+            .help("Maximum number of concurrent downloads from the same host [default: no limit]")
+            .takes_value(true),
+        Arg::with_name("segments")
+            .long("segments")
+            .value_name("SEGMENTS")
+            .help(
+                "Byte ranges of a single download fetched at once, from servers that \
+                   serve them [default: 1]",
+            )
+            .takes_value(true),
+        Arg::with_name("timeout")
+            .short("t")
+            .long("timeout")
+            .value_name("TIMEOUT")
+            .help("Pause after every download in ms")
+            .default_value("300")
+            .takes_value(true),
+    ]
+}
+
+fn missing_argument(description: &str) -> ! {
+    clap::Error::with_description(description, ErrorKind::MissingRequiredArgument).exit()
+}
+
+/// Number of concurrent downloads, concurrent downloads from the same host and pause after
+/// every download.
+fn parse_download_limits(
+    matches: &ArgMatches,
+    profile: &Profile,
+) -> Result<(usize, Option<usize>, usize), Box<RssDumpError>> {
+    let n_downloads: usize = match profile.ndownloads {
+        Some(n_downloads) if matches.occurrences_of("ndownloads") == 0 => n_downloads,
+        _ => matches.value_of("ndownloads").unwrap().parse()?,
+    };
+    info!("Downloading {} items concurrently", n_downloads);
+
+    let host_downloads = match matches.value_of("host-downloads") {
+        Some(host_downloads) => Some(host_downloads.parse()?),
+        None => profile.host_downloads,
+    };
+
+    let timeout: usize = match profile.timeout {
+        Some(timeout) if matches.occurrences_of("timeout") == 0 => timeout,
+        _ => matches.value_of("timeout").unwrap().parse()?,
+    };
+
+    Ok((n_downloads, host_downloads, timeout))
+}
+
+/// Byte ranges of a single download fetched at once.
+fn parse_segments(matches: &ArgMatches, profile: &Profile) -> Result<usize, Box<RssDumpError>> {
+    match matches.value_of("segments") {
+        Some(segments) => Ok(segments.parse()?),
+        None => Ok(profile.segments.unwrap_or(1)),
+    }
+}
+
+/// Configuration to download `rss_feed` into the output location given in the command line
+/// or the profile.
+fn download_config<'a>(
+    matches: &ArgMatches<'_>,
+    profile: &Profile,
+    headers: HeaderMap,
+    client: &reqwest::Client,
+    rss_feed: &'a str,
+) -> Result<DumpConfig<'a>, Box<RssDumpError>> {
+    let (n_downloads, host_downloads, timeout) = parse_download_limits(matches, profile)?;
+
+    Ok(DumpConfig::new_output_is_dir(
+        resolve(matches, "output", profile.output.as_deref())
+            .unwrap_or_else(|| missing_argument("--output is required")),
+        n_downloads,
+        rss_feed,
+        timeout,
+        client.clone(),
+    )
+    .with_headers(headers)
+    .with_host_downloads(host_downloads)
+    .with_segments(parse_segments(matches, profile)?)
+    .with_filename_template(profile.parse_filename_template()?)
+    .with_sanitize_policy(profile.parse_sanitize_policy()?)
+    .with_retry_policy(profile.parse_retry_policy()?)
+    .with_bandwidth_limit(profile.parse_bandwidth_limit()?)
+    .with_space_check(profile.parse_space_check()?))
+}
+
+/// Configurations to download every subscription into its own output location.
+fn subscription_configs<'a>(
+    matches: &ArgMatches<'_>,
+    profile: &Profile,
+    headers: &HeaderMap,
+    client: &reqwest::Client,
+    subscriptions: &'a Subscriptions,
+) -> Result<Vec<DumpConfig<'a>>, Box<RssDumpError>> {
+    let (n_downloads, host_downloads, timeout) = parse_download_limits(matches, profile)?;
+    let segments = parse_segments(matches, profile)?;
+    let filename_template = profile.parse_filename_template()?;
+    let sanitize_policy = profile.parse_sanitize_policy()?;
+    let retry_policy = profile.parse_retry_policy()?;
+    // Every subscription shares the same caps
+    let bandwidth_limit = profile.parse_bandwidth_limit()?;
+    let space_check = profile.parse_space_check()?;
+
+    Ok(subscriptions
+        .iter()
+        .map(|subscription| {
+            DumpConfig::new_output_is_dir(
+                &subscription.output.to_string_lossy(),
+                n_downloads,
+                &subscription.url,
+                timeout,
+                // Every subscription shares the same connections
+                client.clone(),
+            )
+            .with_headers(headers.clone())
+            .with_host_downloads(host_downloads)
+            .with_segments(segments)
+            .with_filename_template(filename_template.clone())
+            .with_sanitize_policy(sanitize_policy)
+            .with_retry_policy(retry_policy.clone())
+            .with_bandwidth_limit(bandwidth_limit.clone())
+            .with_space_check(space_check)
+        })
+        .collect())
+}
+
+/// Create the output directory of `config` and make sure we can write to it.
+async fn prepare_output_dir(config: &DumpConfig<'_>) -> Result<(), Box<RssDumpError>> {
+    config.create_output_dir().await?;
+
+    let is_write = config.is_output_dir_write()?;
+    if is_write {
+        info!(
+            "{} is writable by the current user",
+            config.get_output_display()
+        );
+        Ok(())
+    } else {
+        Err(Box::new(RssDumpError::OutputDirIsNotWritable(
+            config.get_output().to_path_buf(),
+        )))
+    }
+}
+
+/// Download each list of items from its feed, going over the failed downloads again up to
+/// `DOWNLOAD_PASSES` times, after a delay from `retry_policy`. Returns whether every item was
+/// downloaded, and records the run of every feed whose items all were. Cancelling `cancel`
+/// stops the downloads, keeping the interrupted ones in their `.part` files, and prints a
+/// summary.
+async fn download_with_retries(
+    feeds: &[Feed<'_>],
+    mut download_lists: Vec<Vec<Weak<Item>>>,
+    n_downloads: usize,
+    host_downloads: Option<usize>,
+    retry_policy: &RetryPolicy,
+    cancel: &CancellationToken,
+) -> Result<bool, Box<RssDumpError>> {
+    let total: usize = download_lists.iter().map(Vec::len).sum();
+
+    for pass in 1..=DOWNLOAD_PASSES {
+        let jobs: Vec<(&Feed, &[Weak<Item>])> = feeds
+            .iter()
+            .zip(download_lists.iter().map(Vec::as_slice))
+            .collect();
+        let failed_downs = download_feeds(&jobs, n_downloads, host_downloads, cancel).await;
+
+        // Feeds are done once none of their downloads fail, which is recorded only once
+        for ((feed, list), failed) in jobs.iter().zip(&failed_downs) {
+            if failed.is_empty() && (pass == 1 || !list.is_empty()) {
+                if let Err(e) = feed.record_run() {
+                    warn!("Failed to record the run of {}: {}", feed.title(), e);
+                }
+            }
+        }
+
+        let n_failed: usize = failed_downs.iter().map(Vec::len).sum();
+        if n_failed == 0 {
+            return Ok(true);
+        } else if cancel.is_cancelled() {
+            let n_partial = failed_downs
+                .iter()
+                .flatten()
+                .filter(|(_, path, _)| create_part_file_path(path).exists())
+                .count();
+            println!(
+                "Interrupted. {} of {} downloads completed, {} partial downloads kept to \
+                 resume on the next run and {} not started",
+                total - n_failed,
+                total,
+                n_partial,
+                n_failed - n_partial
+            );
+            return Ok(false);
+        } else if pass == DOWNLOAD_PASSES {
+            break;
+        }
+        println!("{n_failed} Downloads failed. Retrying with failed list");
+
+        // Failed downloads keep their partial file so the retry resumes from it
+        for (_, failed_item, _) in failed_downs.iter().flatten() {
+            info!("Keeping partial download of {:?}", failed_item);
+        }
+
+        download_lists = failed_downs
+            .into_iter()
+            .map(|failed| failed.into_iter().map(|(item, _, _)| item).collect())
+            .collect();
+
+        tokio::select! {
+            () = tokio::time::sleep(retry_policy.delay(pass, None)) => (),
+            () = cancel.cancelled() => (),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Download every subscription into its own output location, sharing a single budget of
+/// concurrent downloads. `query` replaces the query of every subscription, while the limits,
+/// headers and filename template come from the profile.
+async fn download_subscriptions(
+    matches: &ArgMatches<'_>,
+    profile: &Profile,
+    headers: &HeaderMap,
+    client: &reqwest::Client,
+    cancel: &CancellationToken,
+    subscriptions_path: &Path,
+    query: Option<&str>,
+) -> Result<(), Box<RssDumpError>> {
+    let subscriptions = Subscriptions::load(subscriptions_path)?;
+    if subscriptions.is_empty() {
+        println!(
+            "There are no subscriptions in {}",
+            subscriptions_path.display()
+        );
+        return Ok(());
+    }
+
+    let configs = subscription_configs(matches, profile, headers, client, &subscriptions)?;
+
+    let mut feeds = vec![];
+    let mut download_lists = vec![];
+    let mut locks = vec![];
+
+    for (subscription, config) in subscriptions.iter().zip(&configs) {
+        // A feed that is down shouldn't stop the others from being downloaded
+        let fetched = match fetch_channel(
+            &subscription.url,
+            true,
+            config.get_client(),
+            headers,
+            config.get_retry_policy(),
+        )
+        .await
+        {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                println!("Failed to fetch {}. Skipping it", subscription.title);
+                warn!("{}: {}", subscription.url, e);
+                continue;
+            }
+        };
+
+        prepare_output_dir(config).await?;
+        match OutputLock::acquire(config.get_output_dir()) {
+            Ok(lock) => locks.push(lock),
+            Err(e) => {
+                println!("Skipping {}. {}", subscription.title, e);
+                continue;
+            }
+        }
+
+        let mut feed = Feed::new(fetched.channel, config).await?;
+        if feed.unchanged_since_last_run(fetched.not_modified_since) {
+            println!(
+                "{} didn't change since the last run. Skipping it",
+                subscription.title
+            );
+            continue;
+        }
+        let query_op = QueryExpr::new(query.unwrap_or(&subscription.query))?.build_query_op();
+        let download_list = feed.build_download_list(&query_op)?;
+
+        info!(
+            "{} items of {} match the query",
+            download_list.len(),
+            feed.title()
+        );
+
+        feeds.push(feed);
+        download_lists.push(download_list);
+    }
+
+    println!(
+        "You are about to download the contents of {} feeds",
+        feeds.len()
+    );
+
+    check_free_space(&feeds.iter().collect::<Vec<_>>(), &mut download_lists).await?;
+
+    let n_downloads = configs.first().map_or(1, DumpConfig::get_n_downloads);
+    let host_downloads = configs.first().and_then(DumpConfig::get_host_downloads);
+    let retry_policy = profile.parse_retry_policy()?;
+    let done = download_with_retries(
+        &feeds,
+        download_lists,
+        n_downloads,
+        host_downloads,
+        &retry_policy,
+        cancel,
+    )
+    .await?;
+    drop(locks);
+
+    if done {
+        println!("Full Download Successfully Completed");
+    } else {
+        println!("Download failed");
+    }
+
+    Ok(())
+}
+
+/// Watch every subscription, each polled on its own interval. The interval given in the
+/// command line takes precedence over the subscription's, which takes precedence over the
+/// profile's.
+async fn watch_subscriptions(
+    matches: &ArgMatches<'_>,
+    profile: &Profile,
+    headers: &HeaderMap,
+    client: &reqwest::Client,
+    cancel: &CancellationToken,
+    subscriptions_path: &Path,
+    query: Option<&str>,
+) -> Result<(), Box<RssDumpError>> {
+    let subscriptions = Subscriptions::load(subscriptions_path)?;
+    let configs = subscription_configs(matches, profile, headers, client, &subscriptions)?;
+
+    let mut feeds = vec![];
+    let mut locks = vec![];
+
+    for (subscription, config) in subscriptions.iter().zip(&configs) {
+        let interval = resolve(
+            matches,
+            "interval",
+            subscription
+                .interval
+                .as_deref()
+                .or(profile.interval.as_deref()),
+        )
+        .unwrap();
+
+        feeds.push(WatchedFeed::new(
+            config,
+            true,
+            query.unwrap_or(&subscription.query),
+            humantime::parse_duration(interval)?,
+        )?);
+
+        prepare_output_dir(config).await?;
+        locks.push(OutputLock::acquire(config.get_output_dir())?);
+    }
+
+    let jitter = humantime::parse_duration(matches.value_of("jitter").unwrap())?;
+    let result = watch(&mut feeds, jitter, cancel).await;
+    drop(locks);
+
+    result
+}
+
+async fn manage_subscriptions(
+    matches: &ArgMatches<'_>,
+    subscriptions_path: &Path,
+    query: &str,
+) -> Result<(), Box<RssDumpError>> {
+    let mut subscriptions = Subscriptions::load(subscriptions_path)?;
+
+    if let Some(import_matches) = matches.subcommand_matches("import") {
+        let content = fs::read(import_matches.value_of("opml").unwrap()).await?;
+        let imported = subscriptions.import_opml(
+            &content,
+            Path::new(import_matches.value_of("output").unwrap()),
+            query,
+        )?;
+        subscriptions.save()?;
+
+        println!(
+            "Imported {imported} feeds into {}",
+            subscriptions_path.display()
+        );
+    } else if let Some(export_matches) = matches.subcommand_matches("export") {
+        fs::write(
+            export_matches.value_of("output").unwrap(),
+            subscriptions.to_opml(),
+        )
+        .await?;
+    } else {
+        for subscription in subscriptions.iter() {
+            println!(
+                "{}\n\tURL: {}\n\tOutput: {}\n\tQuery: {}",
+                subscription.title,
+                subscription.url,
+                subscription.output.display(),
+                subscription.query
+            );
+        }
+    }
+
+    Ok(())
+}
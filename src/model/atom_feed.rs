@@ -0,0 +1,86 @@
+use super::{Channel, Enclosure, Item};
+
+use atom_syndication::{Entry, Link, Person};
+
+const ENCLOSURE_REL: &str = "enclosure";
+const ALTERNATE_REL: &str = "alternate";
+const REPLIES_REL: &str = "replies";
+
+fn find_link<'a>(links: &'a [Link], rel: &str) -> Option<&'a Link> {
+    links.iter().find(|link| link.rel() == rel)
+}
+
+fn join_names(people: &[Person]) -> Option<String> {
+    if people.is_empty() {
+        None
+    } else {
+        Some(
+            people
+                .iter()
+                .map(Person::name)
+                .collect::<Vec<&str>>()
+                .join(", "),
+        )
+    }
+}
+
+impl From<&Link> for Enclosure {
+    fn from(link: &Link) -> Self {
+        Enclosure::new(
+            link.href(),
+            link.mime_type().unwrap_or_default(),
+            link.length().and_then(|length| length.trim().parse().ok()),
+        )
+    }
+}
+
+impl From<&Entry> for Item {
+    fn from(entry: &Entry) -> Self {
+        Self {
+            title: Some(entry.title().to_owned()),
+            link: find_link(entry.links(), ALTERNATE_REL).map(|link| link.href().to_owned()),
+            description: entry
+                .summary()
+                .or_else(|| entry.content().and_then(|content| content.value()))
+                .map(str::to_owned),
+            author: join_names(entry.authors()),
+            categories: entry
+                .categories()
+                .iter()
+                .map(|category| category.term().to_owned())
+                .collect(),
+            comments: find_link(entry.links(), REPLIES_REL).map(|link| link.href().to_owned()),
+            guid: Some(entry.id().to_owned()),
+            pub_date: Some(*entry.published().unwrap_or_else(|| entry.updated())),
+            enclosure: find_link(entry.links(), ENCLOSURE_REL).map(Enclosure::from),
+            duration: None,
+            season: None,
+            episode: None,
+            episode_type: None,
+            explicit: None,
+            rss: None,
+        }
+    }
+}
+
+impl From<atom_syndication::Feed> for Channel {
+    fn from(feed: atom_syndication::Feed) -> Self {
+        Self {
+            title: feed.title().to_owned(),
+            link: find_link(feed.links(), ALTERNATE_REL)
+                .map(|link| link.href().to_owned())
+                .unwrap_or_default(),
+            description: feed.subtitle().unwrap_or_default().to_owned(),
+            language: None,
+            copyright: feed.rights().map(str::to_owned),
+            managing_editor: join_names(feed.authors()),
+            pub_date: Some(*feed.updated()),
+            categories: feed
+                .categories()
+                .iter()
+                .map(|category| category.term().to_owned())
+                .collect(),
+            items: feed.entries().iter().map(Item::from).collect(),
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use super::{Channel, Enclosure, Item};
+use crate::error::RssDumpError;
+
+use chrono::DateTime;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use std::fmt;
+
+pub const JSON_FEED_VERSION: &str = "https://jsonfeed.org/version/1.1";
+
+/// A JSON Feed document, as described in <https://jsonfeed.org/version/1.1>.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JsonFeed {
+    pub version: String,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub home_page_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feed_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<JsonAuthor>,
+    // JSON Feed 1.0 only had a single author
+    #[serde(default, skip_serializing)]
+    pub author: Option<JsonAuthor>,
+    #[serde(default)]
+    pub items: Vec<JsonItem>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JsonAuthor {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JsonItem {
+    #[serde(deserialize_with = "string_or_number")]
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_html: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_published: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<JsonAuthor>,
+    #[serde(default, skip_serializing)]
+    pub author: Option<JsonAuthor>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<JsonAttachment>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JsonAttachment {
+    pub url: String,
+    pub mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_in_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_in_seconds: Option<f64>,
+}
+
+/// Read an id that some feeds give as a number, although JSON Feed requires a string.
+fn string_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Id {
+        String(String),
+        Number(serde_json::Number),
+    }
+
+    Ok(match Id::deserialize(deserializer)? {
+        Id::String(id) => id,
+        Id::Number(id) => id.to_string(),
+    })
+}
+
+impl JsonFeed {
+    pub fn read_from(content: &[u8]) -> Result<Self, Box<RssDumpError>> {
+        Ok(serde_json::from_slice(content)?)
+    }
+}
+
+impl fmt::Display for JsonFeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string_pretty(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", json)
+    }
+}
+
+fn join_names(authors: &[JsonAuthor], author: Option<&JsonAuthor>) -> Option<String> {
+    let names = authors
+        .iter()
+        .chain(author)
+        .filter_map(|author| author.name.as_deref())
+        .collect::<Vec<&str>>();
+
+    if names.is_empty() {
+        None
+    } else {
+        Some(names.join(", "))
+    }
+}
+
+impl From<&JsonAttachment> for Enclosure {
+    fn from(attachment: &JsonAttachment) -> Self {
+        Enclosure::new(
+            &attachment.url,
+            &attachment.mime_type,
+            attachment.size_in_bytes,
+        )
+    }
+}
+
+impl From<JsonItem> for Item {
+    fn from(item: JsonItem) -> Self {
+        Self {
+            author: join_names(&item.authors, item.author.as_ref()),
+            description: item.summary.or(item.content_text).or(item.content_html),
+            pub_date: item
+                .date_published
+                .as_deref()
+                .and_then(|date| DateTime::parse_from_rfc3339(date).ok()),
+            enclosure: item.attachments.first().map(Enclosure::from),
+            duration: item
+                .attachments
+                .first()
+                .and_then(|attachment| attachment.duration_in_seconds)
+                .filter(|seconds| *seconds >= 0.0)
+                .map(|seconds| seconds.round() as u64),
+            title: item.title,
+            link: item.url,
+            categories: item.tags,
+            comments: None,
+            guid: Some(item.id),
+            season: None,
+            episode: None,
+            episode_type: None,
+            explicit: None,
+            rss: None,
+        }
+    }
+}
+
+impl From<JsonFeed> for Channel {
+    fn from(feed: JsonFeed) -> Self {
+        Self {
+            managing_editor: join_names(&feed.authors, feed.author.as_ref()),
+            title: feed.title,
+            link: feed.home_page_url.unwrap_or_default(),
+            description: feed.description.unwrap_or_default(),
+            language: feed.language,
+            copyright: None,
+            pub_date: None,
+            categories: vec![],
+            items: feed.items.into_iter().map(Item::from).collect(),
+        }
+    }
+}
+
+impl From<&Item> for JsonItem {
+    fn from(item: &Item) -> Self {
+        // Every JSON Feed item needs an id and some content
+        let id = item
+            .guid()
+            .or_else(|| item.enclosure().map(Enclosure::url))
+            .or_else(|| item.link())
+            .unwrap_or_default()
+            .to_owned();
+
+        Self {
+            id,
+            url: item.link().map(str::to_owned),
+            title: item.title().map(str::to_owned),
+            content_html: item.description().map(str::to_owned),
+            content_text: if item.description().is_none() {
+                Some(String::new())
+            } else {
+                None
+            },
+            date_published: item.pub_date().map(DateTime::to_rfc3339),
+            authors: item
+                .author()
+                .map(|name| JsonAuthor {
+                    name: Some(name.to_owned()),
+                    url: None,
+                })
+                .into_iter()
+                .collect(),
+            tags: item.categories().to_vec(),
+            attachments: item
+                .enclosure()
+                .map(|enclosure| JsonAttachment {
+                    url: enclosure.url().to_owned(),
+                    mime_type: enclosure.mime_type().to_owned(),
+                    size_in_bytes: enclosure.length(),
+                    duration_in_seconds: item.duration().map(|seconds| seconds as f64),
+                    ..JsonAttachment::default()
+                })
+                .into_iter()
+                .collect(),
+            ..JsonItem::default()
+        }
+    }
+}
+
+impl Channel {
+    /// Convert the channel into a JSON Feed.
+    pub fn to_json_feed(&self) -> JsonFeed {
+        JsonFeed {
+            version: JSON_FEED_VERSION.to_owned(),
+            title: self.title().to_owned(),
+            home_page_url: Some(self.link().to_owned()).filter(|link| !link.is_empty()),
+            description: Some(self.description().to_owned())
+                .filter(|description| !description.is_empty()),
+            language: self.language().map(str::to_owned),
+            authors: self
+                .managing_editor()
+                .map(|name| JsonAuthor {
+                    name: Some(name.to_owned()),
+                    url: None,
+                })
+                .into_iter()
+                .collect(),
+            items: self.items().iter().map(JsonItem::from).collect(),
+            ..JsonFeed::default()
+        }
+    }
+}
@@ -0,0 +1,369 @@
+//! Format-neutral representation of a feed. Every supported input format is converted into a
+//! `Channel`, which is what `Feed` and the queries operate on.
+
+use crate::error::RssDumpError;
+
+use chrono::{DateTime, FixedOffset};
+
+pub mod atom_feed;
+pub mod json_feed;
+pub mod rss_feed;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FeedFormat {
+    Rss,
+    Atom,
+    Json,
+}
+
+impl FeedFormat {
+    /// Detect the format of a feed document. JSON documents are JSON Feeds, XML documents are
+    /// told apart by the name of their root element.
+    pub fn detect(content: &[u8]) -> Option<Self> {
+        let content = String::from_utf8_lossy(content);
+        let mut rest = content.trim_start_matches('\u{feff}');
+
+        if rest.trim_start().starts_with('{') {
+            return Some(FeedFormat::Json);
+        }
+
+        loop {
+            rest = &rest[rest.find('<')?..];
+
+            if rest.starts_with("<?") {
+                rest = &rest[rest.find("?>")? + 2..];
+            } else if rest.starts_with("<!--") {
+                rest = &rest[rest.find("-->")? + 3..];
+            } else if rest.starts_with("<!") {
+                rest = &rest[rest.find('>')? + 1..];
+            } else {
+                let name = rest[1..]
+                    .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+                    .next()?;
+                // Ignore the namespace prefix, if any
+                let name = name.rsplit(':').next()?;
+
+                return match name {
+                    "rss" | "RDF" => Some(FeedFormat::Rss),
+                    "feed" => Some(FeedFormat::Atom),
+                    _ => None,
+                };
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Enclosure {
+    url: String,
+    mime_type: String,
+    length: Option<u64>,
+}
+
+impl Enclosure {
+    /// A length of zero is treated as unknown, since that is what most publishers mean by it.
+    pub fn new(url: &str, mime_type: &str, length: Option<u64>) -> Self {
+        Self {
+            url: url.to_owned(),
+            mime_type: mime_type.to_owned(),
+            length: length.filter(|length| *length > 0),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn mime_type(&self) -> &str {
+        &self.mime_type
+    }
+
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Item {
+    title: Option<String>,
+    link: Option<String>,
+    description: Option<String>,
+    author: Option<String>,
+    categories: Vec<String>,
+    comments: Option<String>,
+    guid: Option<String>,
+    pub_date: Option<DateTime<FixedOffset>>,
+    enclosure: Option<Enclosure>,
+    // Length of the episode, in seconds
+    duration: Option<u64>,
+    season: Option<u64>,
+    episode: Option<u64>,
+    // Lowercase `itunes:episodeType`: full, trailer or bonus
+    episode_type: Option<String>,
+    explicit: Option<bool>,
+    // The item this one was read from, kept so RSS output doesn't lose any extension
+    rss: Option<rss::Item>,
+}
+
+impl Item {
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    pub fn link(&self) -> Option<&str> {
+        self.link.as_deref()
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+
+    pub fn categories(&self) -> &[String] {
+        &self.categories
+    }
+
+    pub fn comments(&self) -> Option<&str> {
+        self.comments.as_deref()
+    }
+
+    pub fn guid(&self) -> Option<&str> {
+        self.guid.as_deref()
+    }
+
+    pub fn pub_date(&self) -> Option<&DateTime<FixedOffset>> {
+        self.pub_date.as_ref()
+    }
+
+    pub fn enclosure(&self) -> Option<&Enclosure> {
+        self.enclosure.as_ref()
+    }
+
+    /// Length of the episode in seconds, from `itunes:duration` or the attachment of a JSON Feed.
+    pub fn duration(&self) -> Option<u64> {
+        self.duration
+    }
+
+    pub fn season(&self) -> Option<u64> {
+        self.season
+    }
+
+    pub fn episode(&self) -> Option<u64> {
+        self.episode
+    }
+
+    /// Kind of episode, "full", "trailer" or "bonus". Items that don't tell are full episodes.
+    pub fn episode_type(&self) -> &str {
+        self.episode_type.as_deref().unwrap_or("full")
+    }
+
+    /// Whether the episode is flagged as explicit, when the feed tells.
+    pub fn explicit(&self) -> Option<bool> {
+        self.explicit
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Channel {
+    title: String,
+    link: String,
+    description: String,
+    language: Option<String>,
+    copyright: Option<String>,
+    managing_editor: Option<String>,
+    pub_date: Option<DateTime<FixedOffset>>,
+    categories: Vec<String>,
+    items: Vec<Item>,
+}
+
+impl Channel {
+    /// Read a feed in any of the supported formats, detecting which one it is.
+    pub fn read_from(content: &[u8]) -> Result<Self, Box<RssDumpError>> {
+        match FeedFormat::detect(content) {
+            Some(FeedFormat::Rss) => Ok(rss::Channel::read_from(content)?.into()),
+            Some(FeedFormat::Atom) => Ok(atom_syndication::Feed::read_from(content)?.into()),
+            Some(FeedFormat::Json) => Ok(json_feed::JsonFeed::read_from(content)?.into()),
+            None => Err(Box::new(RssDumpError::UnknownFeedFormat)),
+        }
+    }
+
+    /// Copy of this channel's metadata with a new title and set of items.
+    pub fn with_items(&self, title: &str, items: Vec<Item>) -> Self {
+        Self {
+            title: title.to_owned(),
+            items,
+            ..self.clone_metadata()
+        }
+    }
+
+    fn clone_metadata(&self) -> Self {
+        Self {
+            title: self.title.clone(),
+            link: self.link.clone(),
+            description: self.description.clone(),
+            language: self.language.clone(),
+            copyright: self.copyright.clone(),
+            managing_editor: self.managing_editor.clone(),
+            pub_date: self.pub_date,
+            categories: self.categories.clone(),
+            items: vec![],
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn link(&self) -> &str {
+        &self.link
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+
+    pub fn copyright(&self) -> Option<&str> {
+        self.copyright.as_deref()
+    }
+
+    pub fn managing_editor(&self) -> Option<&str> {
+        self.managing_editor.as_deref()
+    }
+
+    pub fn pub_date(&self) -> Option<&DateTime<FixedOffset>> {
+        self.pub_date.as_ref()
+    }
+
+    pub fn categories(&self) -> &[String] {
+        &self.categories
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ATOM_FEED: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<!-- An Atom feed -->
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Atom Feed</title>
+  <id>urn:feed</id>
+  <updated>2021-01-02T00:00:00Z</updated>
+  <link href="http://example.com/"/>
+  <entry>
+    <title>Episode 1</title>
+    <id>urn:episode:1</id>
+    <updated>2021-01-02T00:00:00Z</updated>
+    <published>2021-01-01T10:00:00+01:00</published>
+    <summary>Cheese</summary>
+    <link href="http://example.com/1"/>
+    <link rel="enclosure" type="audio/mpeg" length="1234" href="http://example.com/1.mp3"/>
+  </entry>
+</feed>"#;
+
+    #[test]
+    fn detect_feed_format() {
+        assert_eq!(
+            FeedFormat::detect(ATOM_FEED.as_bytes()),
+            Some(FeedFormat::Atom)
+        );
+        assert_eq!(
+            FeedFormat::detect(b"\xef\xbb\xbf<?xml version=\"1.0\"?><rss version=\"2.0\"></rss>"),
+            Some(FeedFormat::Rss)
+        );
+        assert_eq!(
+            FeedFormat::detect(b"<rdf:RDF xmlns:rdf=\"x\"></rdf:RDF>"),
+            Some(FeedFormat::Rss)
+        );
+        assert_eq!(
+            FeedFormat::detect(b"  {\"version\": \"https://jsonfeed.org/version/1.1\"}"),
+            Some(FeedFormat::Json)
+        );
+        assert_eq!(FeedFormat::detect(b"<html></html>"), None);
+        assert_eq!(FeedFormat::detect(b"not a feed"), None);
+    }
+
+    #[test]
+    fn read_atom_feed() {
+        let channel = Channel::read_from(ATOM_FEED.as_bytes()).unwrap();
+        assert_eq!(channel.title(), "Atom Feed");
+        assert_eq!(channel.link(), "http://example.com/");
+        assert_eq!(channel.items().len(), 1);
+
+        let item = &channel.items()[0];
+        assert_eq!(item.title(), Some("Episode 1"));
+        assert_eq!(item.guid(), Some("urn:episode:1"));
+        assert_eq!(item.link(), Some("http://example.com/1"));
+        assert_eq!(item.description(), Some("Cheese"));
+        assert_eq!(
+            item.pub_date().unwrap().to_rfc3339(),
+            "2021-01-01T10:00:00+01:00"
+        );
+        assert_eq!(
+            item.enclosure(),
+            Some(&Enclosure::new(
+                "http://example.com/1.mp3",
+                "audio/mpeg",
+                Some(1234)
+            ))
+        );
+    }
+
+    #[test]
+    fn json_feed_round_trip() {
+        let json = r#"{
+            "version": "https://jsonfeed.org/version/1.1",
+            "title": "Json Feed",
+            "home_page_url": "http://example.com/",
+            "author": { "name": "Someone" },
+            "items": [{
+                "id": "1",
+                "title": "Episode 1",
+                "content_text": "Cheese",
+                "date_published": "2021-01-01T10:00:00+01:00",
+                "tags": ["Bonus"],
+                "attachments": [{
+                    "url": "http://example.com/1.mp3",
+                    "mime_type": "audio/mpeg",
+                    "size_in_bytes": 1234
+                }]
+            }]
+        }"#;
+
+        let channel = Channel::read_from(json.as_bytes()).unwrap();
+        assert_eq!(channel.title(), "Json Feed");
+        assert_eq!(channel.managing_editor(), Some("Someone"));
+
+        let item = &channel.items()[0];
+        assert_eq!(item.guid(), Some("1"));
+        assert_eq!(item.description(), Some("Cheese"));
+        assert_eq!(item.categories(), &["Bonus".to_owned()]);
+        assert_eq!(
+            item.enclosure(),
+            Some(&Enclosure::new(
+                "http://example.com/1.mp3",
+                "audio/mpeg",
+                Some(1234)
+            ))
+        );
+
+        let output = channel.to_json_feed().to_string();
+        let reread = Channel::read_from(output.as_bytes()).unwrap();
+        assert_eq!(reread, channel);
+
+        let numeric_id = json.replace(r#""id": "1""#, r#""id": 1"#);
+        let channel = Channel::read_from(numeric_id.as_bytes()).unwrap();
+        assert_eq!(channel.items()[0].guid(), Some("1"));
+    }
+}
@@ -0,0 +1,171 @@
+use super::{Channel, Enclosure, Item};
+use crate::error::RssDumpError;
+use crate::utils::parse_duration;
+
+use chrono::{DateTime, FixedOffset, Local};
+use rss::{CategoryBuilder, ChannelBuilder, EnclosureBuilder, GuidBuilder, ItemBuilder};
+
+/// Parse an RSS date. RFC 2822 is what the spec mandates, but RFC 3339 is common enough to
+/// be worth accepting too.
+pub fn parse_date(date: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc2822(date)
+        .or_else(|_| DateTime::parse_from_rfc3339(date))
+        .map_err(|_| info!("Failed to parse date. {}", date))
+        .ok()
+}
+
+impl From<&rss::Enclosure> for Enclosure {
+    fn from(enclosure: &rss::Enclosure) -> Self {
+        Enclosure::new(
+            enclosure.url(),
+            enclosure.mime_type(),
+            enclosure.length().trim().parse().ok(),
+        )
+    }
+}
+
+/// Value of the Podcasting 2.0 element `podcast:name` of `item`, when it has one.
+fn podcast_ext<'item>(item: &'item rss::Item, name: &str) -> Option<&'item str> {
+    item.extensions()
+        .get("podcast")?
+        .get(name)?
+        .first()?
+        .value()
+}
+
+impl From<rss::Item> for Item {
+    fn from(item: rss::Item) -> Self {
+        Self {
+            title: item.title().map(str::to_owned),
+            link: item.link().map(str::to_owned),
+            description: item.description().map(str::to_owned),
+            author: item.author().map(str::to_owned),
+            categories: item
+                .categories()
+                .iter()
+                .map(|category| category.name().to_owned())
+                .collect(),
+            comments: item.comments().map(str::to_owned),
+            guid: item.guid().map(|guid| guid.value().to_owned()),
+            pub_date: item.pub_date().and_then(parse_date),
+            enclosure: item.enclosure().map(Enclosure::from),
+            duration: item
+                .itunes_ext()
+                .and_then(|itunes| itunes.duration())
+                .and_then(parse_duration),
+            season: item
+                .itunes_ext()
+                .and_then(|itunes| itunes.season())
+                .or_else(|| podcast_ext(&item, "season"))
+                .and_then(|season| season.trim().parse().ok()),
+            episode: item
+                .itunes_ext()
+                .and_then(|itunes| itunes.episode())
+                .or_else(|| podcast_ext(&item, "episode"))
+                .and_then(|episode| episode.trim().parse().ok()),
+            episode_type: item
+                .itunes_ext()
+                .and_then(|itunes| itunes.episode_type())
+                .map(|episode_type| episode_type.trim().to_lowercase()),
+            explicit: item
+                .itunes_ext()
+                .and_then(|itunes| itunes.explicit())
+                .and_then(|explicit| match explicit.trim().to_lowercase().as_str() {
+                    "yes" | "true" | "explicit" => Some(true),
+                    "no" | "false" | "clean" => Some(false),
+                    _ => None,
+                }),
+            rss: Some(item),
+        }
+    }
+}
+
+impl From<rss::Channel> for Channel {
+    fn from(channel: rss::Channel) -> Self {
+        Self {
+            title: channel.title().to_owned(),
+            link: channel.link().to_owned(),
+            description: channel.description().to_owned(),
+            language: channel.language().map(str::to_owned),
+            copyright: channel.copyright().map(str::to_owned),
+            managing_editor: channel.managing_editor().map(str::to_owned),
+            pub_date: channel.pub_date().and_then(parse_date),
+            categories: channel
+                .categories()
+                .iter()
+                .map(|category| category.name().to_owned())
+                .collect(),
+            items: channel.items().iter().cloned().map(Item::from).collect(),
+        }
+    }
+}
+
+impl From<&Item> for rss::Item {
+    fn from(item: &Item) -> Self {
+        // Items that came from an RSS feed are written back untouched
+        if let Some(rss_item) = &item.rss {
+            return rss_item.clone();
+        }
+
+        ItemBuilder::default()
+            .title(item.title.clone())
+            .link(item.link.clone())
+            .description(item.description.clone())
+            .author(item.author.clone())
+            .categories(
+                item.categories
+                    .iter()
+                    .map(|category| CategoryBuilder::default().name(category.as_str()).build())
+                    .collect::<Result<Vec<rss::Category>, String>>()
+                    .unwrap_or_default(),
+            )
+            .comments(item.comments.clone())
+            .guid(item.guid.as_ref().and_then(|guid| {
+                GuidBuilder::default()
+                    .value(guid.as_str())
+                    .permalink(false)
+                    .build()
+                    .ok()
+            }))
+            .pub_date(item.pub_date.map(|date| date.to_rfc2822()))
+            .enclosure(item.enclosure.as_ref().and_then(|enclosure| {
+                EnclosureBuilder::default()
+                    .url(enclosure.url())
+                    .mime_type(enclosure.mime_type())
+                    .length(enclosure.length().unwrap_or(0).to_string())
+                    .build()
+                    .ok()
+            }))
+            .build()
+            .unwrap_or_default()
+    }
+}
+
+impl Channel {
+    /// Convert the channel into an RSS channel, stamped with the current time and `generator`.
+    pub fn to_rss(&self, generator: &str) -> Result<rss::Channel, Box<RssDumpError>> {
+        Ok(ChannelBuilder::default()
+            .title(self.title())
+            .link(self.link())
+            .description(self.description())
+            .language(self.language().map(str::to_owned))
+            .copyright(self.copyright().map(str::to_owned))
+            .managing_editor(self.managing_editor().map(str::to_owned))
+            .pub_date(self.pub_date().map(DateTime::to_rfc2822))
+            .last_build_date(Local::now().to_rfc2822())
+            .categories(
+                self.categories()
+                    .iter()
+                    .map(|category| CategoryBuilder::default().name(category.as_str()).build())
+                    .collect::<Result<Vec<rss::Category>, String>>()?,
+            )
+            .generator(Some(generator.to_owned()))
+            .items(
+                self.items()
+                    .iter()
+                    .map(rss::Item::from)
+                    .collect::<Vec<rss::Item>>(),
+            )
+            .build()?)
+    }
+}
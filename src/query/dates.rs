@@ -0,0 +1,111 @@
+use super::error::QueryError;
+use super::options::rewrite_elements;
+use super::RANGE_DELIMITER;
+use chrono::{Datelike, Duration, NaiveDate};
+use std::convert::TryFrom;
+
+/// Value of a date query selecting the items published since the last run that downloaded
+/// everything it selected into the output directory. It depends on the output directory, so it
+/// is not resolved to dates.
+pub const SINCE_LAST_RUN: &str = "since-last-run";
+
+/// Prefix of the periods ending today, as in `last-30d`.
+const LAST_PREFIX: &str = "last-";
+/// Prefix of the current calendar periods, as in `this-year`.
+const THIS_PREFIX: &str = "this-";
+
+/// Replace the relative dates of a date query with the absolute dates they stand for on
+/// `today`, so the query can be parsed like any other date query.
+///
+/// `today` and `yesterday` can be used anywhere a date can. `last-N` followed by `d`, `w`, `m`
+/// or `y` is the range of the last N days, weeks, months or years up to today, and
+/// `this-week`, `this-month` and `this-year` the range from the start of the current week,
+/// month or year. Periods can't be the bounds of a range. Anything else is left as is.
+pub fn resolve_relative_dates(input: &str, today: NaiveDate) -> Result<String, QueryError> {
+    rewrite_elements(input, |element| {
+        let element = element.trim();
+        match element
+            .strip_prefix('[')
+            .and_then(|e| e.strip_suffix(']'))
+            .and_then(|inside| inside.split_once(RANGE_DELIMITER))
+        {
+            Some((start, end)) => Ok(format!(
+                "[{}{}{}]",
+                resolve_day(start.trim(), today),
+                RANGE_DELIMITER,
+                resolve_day(end.trim(), today)
+            )),
+            None => match resolve_period(element, today)? {
+                Some((start, end)) if start < end => {
+                    Ok(format!("[{}{}{}]", start, RANGE_DELIMITER, end))
+                }
+                Some((start, _)) => Ok(start.to_string()),
+                None => Ok(resolve_day(element, today)),
+            },
+        }
+    })
+}
+
+/// The date `day` stands for, if it is a relative day.
+fn resolve_day(day: &str, today: NaiveDate) -> String {
+    match day {
+        "today" => today.to_string(),
+        "yesterday" => (today - Duration::days(1)).to_string(),
+        _ => day.to_owned(),
+    }
+}
+
+/// First and last day of the period `period` stands for, if it is a relative period.
+fn resolve_period(
+    period: &str,
+    today: NaiveDate,
+) -> Result<Option<(NaiveDate, NaiveDate)>, QueryError> {
+    let invalid = || QueryError::InvalidRelativeDate(period.to_owned());
+
+    if let Some(length) = period.strip_prefix(LAST_PREFIX) {
+        let unit_start = length.len() - length.chars().last().map_or(0, char::len_utf8);
+        let count: u32 = length[..unit_start].parse().map_err(|_| invalid())?;
+
+        let start = match &length[unit_start..] {
+            "d" => today.checked_sub_signed(Duration::days(count.into())),
+            "w" => today.checked_sub_signed(Duration::weeks(count.into())),
+            "m" => months_before(today, count),
+            "y" => count
+                .checked_mul(12)
+                .and_then(|months| months_before(today, months)),
+            _ => return Err(invalid()),
+        };
+        return start.map(|start| Some((start, today))).ok_or_else(invalid);
+    }
+
+    if let Some(unit) = period.strip_prefix(THIS_PREFIX) {
+        let start = match unit {
+            "week" => today
+                .checked_sub_signed(Duration::days(
+                    today.weekday().num_days_from_monday().into(),
+                ))
+                .ok_or_else(invalid)?,
+            "month" => today.with_day(1).ok_or_else(invalid)?,
+            "year" => today.with_ordinal(1).ok_or_else(invalid)?,
+            _ => return Err(invalid()),
+        };
+        return Ok(Some((start, today)));
+    }
+
+    Ok(None)
+}
+
+/// The same day `months` months before `date`, or the last day of that month when it is
+/// shorter. `None` when that is out of the range of dates.
+fn months_before(date: NaiveDate, months: u32) -> Option<NaiveDate> {
+    let total = date
+        .year()
+        .checked_mul(12)?
+        .checked_add(date.month0() as i32)?
+        .checked_sub(i32::try_from(months).ok()?)?;
+    let (year, month) = (total.div_euclid(12), total.rem_euclid(12) as u32 + 1);
+
+    (1..=date.day())
+        .rev()
+        .find_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+}
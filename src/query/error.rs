@@ -5,6 +5,13 @@ use std::str::FromStr;
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum QueryError {
     InvalidQueryOption(String),
+    EmptyQuery,
+    UnexpectedToken(String),
+    MissingOperand(String),
+    UnbalancedParentheses(String),
+    InvalidRegex(String),
+    InvalidQuantity(String),
+    InvalidRelativeDate(String),
     Number(ParserError<u64>),
     Date(ParserError<NaiveDate>),
     Str(ParserError<String>),
@@ -20,6 +27,29 @@ impl fmt::Display for QueryError {
                 "Invalid Query Error: '{}' is not a valid query option",
                 q
             )?,
+            QueryError::EmptyQuery => writeln!(f, "Invalid Query Error: query is empty")?,
+            QueryError::UnexpectedToken(t) => {
+                writeln!(f, "Invalid Query Error: unexpected '{}' in query", t)?
+            }
+            QueryError::MissingOperand(o) => {
+                writeln!(f, "Invalid Query Error: missing an operand after '{}'", o)?
+            }
+            QueryError::UnbalancedParentheses(q) => writeln!(
+                f,
+                "Invalid Query Error: parentheses are not balanced in '{}'",
+                q
+            )?,
+            QueryError::InvalidRegex(r) => {
+                writeln!(f, "Regex Query Error: invalid regular expression {}", r)?
+            }
+            QueryError::InvalidQuantity(q) => writeln!(
+                f,
+                "Quantity Query Error: '{}' is not a valid size or duration",
+                q
+            )?,
+            QueryError::InvalidRelativeDate(d) => {
+                writeln!(f, "Date Query Error: '{}' is not a valid relative date", d)?
+            }
             QueryError::Number(n) => writeln!(f, "Number Query Error: {}", n)?,
             QueryError::Date(n) => writeln!(f, "Date Query Error: {}", n)?,
             QueryError::Str(n) => writeln!(f, "String Query Error: {}", n)?,
@@ -0,0 +1,297 @@
+use super::error::QueryError;
+use super::{Query, QueryOp};
+use crate::feed::Feed;
+use crate::model::Item;
+use chrono::{Local, NaiveDate};
+
+const AND_KEYWORD: &str = "AND";
+const OR_KEYWORD: &str = "OR";
+const NOT_KEYWORD: &str = "NOT";
+
+/// A boolean combination of query options.
+///
+/// The grammar, from lowest to highest precedence, is:
+///
+/// ```text
+/// expr    := and_expr ("OR" and_expr)*
+/// and_expr := unary ("AND" unary)*
+/// unary   := "NOT" unary | primary
+/// primary := "(" expr ")" | option
+/// ```
+///
+/// where `option` is any query option accepted by `Query::new` (e.g. `title:my title` or
+/// `number:{1, [5:10]}`).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum QueryExpr<'input> {
+    Option(Query<'input>),
+    Not(Box<QueryExpr<'input>>),
+    And(Box<QueryExpr<'input>>, Box<QueryExpr<'input>>),
+    Or(Box<QueryExpr<'input>>, Box<QueryExpr<'input>>),
+}
+
+impl<'input> QueryExpr<'input> {
+    pub fn new(input: &'input str) -> Result<Self, QueryError> {
+        Self::new_at(input, Local::today().naive_local())
+    }
+
+    /// Parse an expression, resolving relative dates against `today`.
+    pub fn new_at(input: &'input str, today: NaiveDate) -> Result<Self, QueryError> {
+        let tokens = tokenize(input);
+
+        if tokens.is_empty() {
+            return Err(QueryError::EmptyQuery);
+        }
+
+        let mut parser = ExprParser {
+            tokens: &tokens,
+            pos: 0,
+            today,
+        };
+        let expr = parser.parse_or()?;
+
+        match parser.peek() {
+            None => Ok(expr),
+            Some(Token::RParen) => Err(QueryError::UnbalancedParentheses(input.to_owned())),
+            Some(token) => Err(QueryError::UnexpectedToken(token.to_string())),
+        }
+    }
+
+    /// Compile the expression into a single closure that can be evaluated against every item.
+    pub fn build_query_op(self) -> QueryOp<'input> {
+        match self {
+            QueryExpr::Option(query) => query.build_query_op(),
+            QueryExpr::Not(expr) => {
+                let func = expr.build_query_op();
+                Box::new(move |args: (&Item, usize, &Feed)| -> bool { !func(args) })
+            }
+            QueryExpr::And(lhs, rhs) => {
+                let lhs = lhs.build_query_op();
+                let rhs = rhs.build_query_op();
+                Box::new(move |args: (&Item, usize, &Feed)| -> bool { lhs(args) && rhs(args) })
+            }
+            QueryExpr::Or(lhs, rhs) => {
+                let lhs = lhs.build_query_op();
+                let rhs = rhs.build_query_op();
+                Box::new(move |args: (&Item, usize, &Feed)| -> bool { lhs(args) || rhs(args) })
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Token<'input> {
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+    Option(&'input str),
+}
+
+impl std::fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::And => write!(f, "{}", AND_KEYWORD),
+            Token::Or => write!(f, "{}", OR_KEYWORD),
+            Token::Not => write!(f, "{}", NOT_KEYWORD),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::Option(o) => write!(f, "{}", o),
+        }
+    }
+}
+
+/// Split the input into operators, parentheses and query options.
+///
+/// Keywords are only recognized as whole words outside of ranges and sets. Parentheses only
+/// group when an option could start at that position, so balanced parentheses inside an option
+/// (e.g. `title:Part (1)`) are kept as part of it.
+fn tokenize(input: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut option_start: Option<usize> = None;
+    // Depth of '[' and '{' in the current option
+    let mut bracket_depth = 0_usize;
+    // Parentheses that belong to the current option
+    let mut literal_parens = 0_usize;
+    let mut at_word_boundary = true;
+
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if bracket_depth == 0 && at_word_boundary {
+            let keyword = [
+                (AND_KEYWORD, Token::And),
+                (OR_KEYWORD, Token::Or),
+                (NOT_KEYWORD, Token::Not),
+            ]
+            .iter()
+            .find(|(keyword, _)| {
+                input[i..].starts_with(keyword)
+                    && input[i + keyword.len()..]
+                        .chars()
+                        .next()
+                        .map_or(true, |next| {
+                            next.is_whitespace() || next == '(' || next == ')'
+                        })
+            })
+            .copied();
+
+            if let Some((keyword, token)) = keyword {
+                flush(input, &mut tokens, &mut option_start, i);
+                tokens.push(token);
+                literal_parens = 0;
+                // Skip the rest of the keyword
+                for _ in 1..keyword.len() {
+                    chars.next();
+                }
+                at_word_boundary = false;
+                continue;
+            }
+        }
+
+        match c {
+            '[' | '{' => bracket_depth += 1,
+            ']' | '}' => bracket_depth = bracket_depth.saturating_sub(1),
+            '(' if bracket_depth == 0 => {
+                if option_start.is_none() {
+                    tokens.push(Token::LParen);
+                    at_word_boundary = true;
+                    continue;
+                }
+                literal_parens += 1;
+            }
+            ')' if bracket_depth == 0 => {
+                if literal_parens == 0 {
+                    flush(input, &mut tokens, &mut option_start, i);
+                    tokens.push(Token::RParen);
+                    at_word_boundary = true;
+                    continue;
+                }
+                literal_parens -= 1;
+            }
+            // Regular expressions may contain anything up to their closing '/'
+            '~' if input[i + 1..].starts_with('/') => {
+                chars.next();
+                let mut escaped = false;
+                for (_, c) in chars.by_ref() {
+                    match c {
+                        '/' if !escaped => break,
+                        '\\' => escaped = !escaped,
+                        _ => escaped = false,
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        at_word_boundary = c.is_whitespace();
+        if option_start.is_none() && !c.is_whitespace() {
+            option_start = Some(i);
+        }
+    }
+
+    flush(input, &mut tokens, &mut option_start, input.len());
+
+    tokens
+}
+
+/// Push the option that started at `option_start` and ends at `end`, if there is one.
+fn flush<'input>(
+    input: &'input str,
+    tokens: &mut Vec<Token<'input>>,
+    option_start: &mut Option<usize>,
+    end: usize,
+) {
+    if let Some(start) = option_start.take() {
+        let option = input[start..end].trim();
+        if !option.is_empty() {
+            tokens.push(Token::Option(option));
+        }
+    }
+}
+
+struct ExprParser<'tokens, 'input> {
+    tokens: &'tokens [Token<'input>],
+    pos: usize,
+    today: NaiveDate,
+}
+
+impl<'tokens, 'input> ExprParser<'tokens, 'input> {
+    fn peek(&self) -> Option<Token<'input>> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<Token<'input>> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<QueryExpr<'input>, QueryError> {
+        let mut lhs = self.parse_and()?;
+
+        while self.peek() == Some(Token::Or) {
+            self.next();
+            let rhs = self.parse_and()?;
+            lhs = QueryExpr::Or(Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<QueryExpr<'input>, QueryError> {
+        let mut lhs = self.parse_unary()?;
+
+        while self.peek() == Some(Token::And) {
+            self.next();
+            let rhs = self.parse_unary()?;
+            lhs = QueryExpr::And(Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<QueryExpr<'input>, QueryError> {
+        if self.peek() == Some(Token::Not) {
+            self.next();
+            let expr = self.parse_unary()?;
+            Ok(QueryExpr::Not(Box::new(expr)))
+        } else {
+            self.parse_primary()
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<QueryExpr<'input>, QueryError> {
+        // The token before the one we are about to consume, for error reporting
+        let previous = self
+            .pos
+            .checked_sub(1)
+            .and_then(|pos| self.tokens.get(pos))
+            .map(ToString::to_string);
+
+        match self.next() {
+            Some(Token::Option(option)) => {
+                Ok(QueryExpr::Option(Query::new_at(option, self.today)?))
+            }
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    Some(token) => Err(QueryError::UnexpectedToken(token.to_string())),
+                    None => Err(QueryError::UnbalancedParentheses(
+                        self.tokens
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<String>>()
+                            .join(" "),
+                    )),
+                }
+            }
+            Some(token @ Token::RParen) if previous.is_none() => {
+                Err(QueryError::UnexpectedToken(token.to_string()))
+            }
+            Some(_) | None => Err(QueryError::MissingOperand(
+                previous.unwrap_or_else(|| "".to_owned()),
+            )),
+        }
+    }
+}
@@ -1,8 +1,10 @@
 use crate::feed::Feed;
-use rss::Item;
-use std::convert::TryFrom;
+use crate::model::Item;
+use chrono::{Local, NaiveDate};
 
+pub mod dates;
 pub mod error;
+pub mod expr;
 pub mod options;
 pub mod parser;
 pub mod rangeset;
@@ -13,7 +15,7 @@ use self::options::*;
 pub type QueryOp<'a> = Box<dyn Fn((&Item, usize, &Feed)) -> bool + 'a + Send + Sync>;
 pub const RANGE_DELIMITER: char = ':';
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Query<'input> {
     options: &'input str,
     op: QueryOperationOptions,
@@ -21,17 +23,27 @@ pub struct Query<'input> {
 
 impl<'input> Query<'input> {
     pub fn new(options: &'input str) -> Result<Self, QueryError> {
-        let op = QueryOperationOptions::try_from(options)?;
+        Self::new_at(options, Local::today().naive_local())
+    }
+
+    /// Parse a query option, resolving relative dates against `today`.
+    pub fn new_at(options: &'input str, today: NaiveDate) -> Result<Self, QueryError> {
+        let op = QueryOperationOptions::parse(options, today)?;
         Ok(Self { options, op })
     }
 
     pub fn build_query_op(self) -> QueryOp<'input> {
         self.op.build_func()
     }
+
+    pub fn get_options(&self) -> &'input str {
+        self.options
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::expr::QueryExpr;
     use super::rangeset::{Range, RangeOrSet, Set};
     use super::*;
     use std::collections::HashSet;
@@ -505,4 +517,395 @@ mod tests {
             }))
         );
     }
+
+    #[test]
+    fn new_query_valid() {
+        assert_eq!(Query::new("new").unwrap().op, QueryOperationOptions::New);
+        assert_eq!(
+            Query::new("new:5").err().unwrap(),
+            QueryError::InvalidQueryOption("new:5".to_owned())
+        );
+    }
+
+    #[test]
+    fn text_query_modifiers() {
+        assert_eq!(
+            Query::new("title:ci:word:cheese").unwrap().op,
+            QueryOperationOptions::Title(TextMatcher::Contains {
+                values: RangeOrSet::Range(Range {
+                    start: "cheese".to_owned(),
+                    end: None
+                }),
+                case_insensitive: true,
+                whole_word: true,
+            })
+        );
+        assert_eq!(
+            Query::new(r"description~/^Episode \d+:/i").unwrap().op,
+            QueryOperationOptions::Description(TextMatcher::Regex {
+                pattern: r"^Episode \d+:".to_owned(),
+                flags: "i".to_owned(),
+            })
+        );
+        assert!(matches!(
+            Query::new("title~/(unclosed/").err().unwrap(),
+            QueryError::InvalidRegex(_)
+        ));
+        assert!(matches!(
+            Query::new("title~/cheese/q").err().unwrap(),
+            QueryError::InvalidRegex(_)
+        ));
+        assert!(matches!(
+            Query::new("title~cheese").err().unwrap(),
+            QueryError::InvalidRegex(_)
+        ));
+        assert_eq!(
+            Query::new("number~/1/").err().unwrap(),
+            QueryError::InvalidQueryOption("number~/1/".to_owned())
+        );
+    }
+
+    #[test]
+    fn text_query_matching() {
+        let matches = |options: &str, text: &str| -> bool {
+            match Query::new(options).unwrap().op {
+                QueryOperationOptions::Title(matcher) => matcher.build_func()(text),
+                _ => unreachable!(),
+            }
+        };
+
+        assert!(matches("title:Cheese", "Cheese tasting"));
+        assert!(!matches("title:Cheese", "cheese tasting"));
+        assert!(matches("title:ci:Cheese", "cheese tasting"));
+        assert!(matches("title:word:cheese", "The cheese, tasted"));
+        assert!(!matches(
+            "title:word:cheese",
+            "Cheesecake and cheesemongers"
+        ));
+        assert!(matches("title:ci:word:{cake, cheese}", "CAKE"));
+        assert!(matches("title:word:a.b", "a.b"));
+        assert!(!matches("title:word:a.b", "axb"));
+        assert!(matches(r"title~/^Episode \d+:/", "Episode 12: Cheese"));
+        assert!(!matches(r"title~/^Episode \d+:/", "Bonus Episode 12:"));
+        assert!(matches(r"title~/^episode \d+:/i", "EPISODE 12: Cheese"));
+        assert!(matches(r"title~/AC\/DC/", "AC/DC live"));
+    }
+
+    #[test]
+    fn item_field_queries() {
+        assert_eq!(
+            Query::new("enclosure.url:cdn.example.com").unwrap().op,
+            QueryOperationOptions::Field(
+                ItemField::EnclosureUrl,
+                TextMatcher::Contains {
+                    values: RangeOrSet::Range(Range {
+                        start: "cdn.example.com".to_owned(),
+                        end: None
+                    }),
+                    case_insensitive: false,
+                    whole_word: false,
+                }
+            )
+        );
+        assert!(matches!(
+            Query::new("category~/^bonus$/i").unwrap().op,
+            QueryOperationOptions::Field(ItemField::Category, TextMatcher::Regex { .. })
+        ));
+        assert_eq!(
+            Query::new("author").err().unwrap(),
+            QueryError::InvalidQueryOption("author".to_owned())
+        );
+
+        let channel = crate::model::Channel::read_from(
+            br#"<rss version="2.0"><channel><title>T</title><link>l</link><description>d</description>
+            <item>
+                <title>Episode 1</title>
+                <author>host@example.com</author>
+                <category>Interview</category>
+                <category>Bonus</category>
+                <enclosure url="http://cdn.example.com/1.mp3" type="audio/mpeg" length="10"/>
+            </item>
+            </channel></rss>"#,
+        )
+        .unwrap();
+        let item = &channel.items()[0];
+        assert_eq!(ItemField::Category.values(item), ["Interview", "Bonus"]);
+        assert_eq!(ItemField::Author.values(item), ["host@example.com"]);
+        assert_eq!(ItemField::EnclosureType.values(item), ["audio/mpeg"]);
+        assert!(ItemField::Comments.values(item).is_empty());
+    }
+
+    #[test]
+    fn size_and_duration_queries() {
+        assert_eq!(
+            Query::new("size:[100MB:2GB]").unwrap().op,
+            QueryOperationOptions::Size(RangeOrSet::Range(Range {
+                start: 100 * 1024 * 1024,
+                end: Some(2 * 1024 * 1024 * 1024)
+            }))
+        );
+        assert_eq!(
+            Query::new("duration:[00:30:00:01:30:00]").unwrap().op,
+            QueryOperationOptions::Duration(RangeOrSet::Range(Range {
+                start: 30 * 60,
+                end: Some(90 * 60)
+            }))
+        );
+        assert_eq!(
+            Query::new("duration:{[5:00:10:00], 1h 30m}").unwrap().op,
+            QueryOperationOptions::Duration(RangeOrSet::Set(Set {
+                contents: {
+                    let mut set = HashSet::new();
+                    set.insert(Range {
+                        start: 5 * 60,
+                        end: Some(10 * 60),
+                    });
+                    set.insert(Range {
+                        start: 90 * 60,
+                        end: None,
+                    });
+                    set
+                }
+            }))
+        );
+        assert_eq!(
+            Query::new("size:[2GB:100MB]").err().unwrap(),
+            QueryError::Number(ParserError::EndLessThanStart {
+                start: 2 * 1024 * 1024 * 1024,
+                end: 100 * 1024 * 1024
+            })
+        );
+        assert_eq!(
+            Query::new("size:[100MB:lots]").err().unwrap(),
+            QueryError::InvalidQuantity("lots".to_owned())
+        );
+        assert_eq!(
+            Query::new("duration:[1:00:00]").err().unwrap(),
+            QueryError::Number(ParserError::MissingRangeDelimiter("[1:00:00]".to_owned()))
+        );
+        assert_eq!(
+            Query::new("size:1e30T").err().unwrap(),
+            QueryError::InvalidQuantity("1e30T".to_owned())
+        );
+        assert_eq!(
+            Query::new("duration:99999999999999999:0:0").err().unwrap(),
+            QueryError::InvalidQuantity("99999999999999999:0:0".to_owned())
+        );
+        assert_eq!(
+            Query::new("size:[100MB").err().unwrap(),
+            QueryError::Number(ParserError::Unfinished("[100MB".to_owned()))
+        );
+    }
+
+    #[test]
+    fn namespace_queries() {
+        assert_eq!(
+            Query::new("episode:[1:20]").unwrap().op,
+            QueryOperationOptions::Episode(RangeOrSet::Range(Range {
+                start: 1,
+                end: Some(20)
+            }))
+        );
+        assert_eq!(
+            Query::new("season:3").unwrap().op,
+            QueryOperationOptions::Season(RangeOrSet::Range(Range {
+                start: 3,
+                end: None
+            }))
+        );
+        assert!(matches!(
+            Query::new("type:full").unwrap().op,
+            QueryOperationOptions::Field(ItemField::EpisodeType, _)
+        ));
+        assert_eq!(
+            Query::new("explicit").unwrap().op,
+            QueryOperationOptions::Explicit(true)
+        );
+        assert_eq!(
+            Query::new("explicit:no").unwrap().op,
+            QueryOperationOptions::Explicit(false)
+        );
+        assert_eq!(
+            Query::new("explicit:maybe").err().unwrap(),
+            QueryError::InvalidQueryOption("explicit:maybe".to_owned())
+        );
+    }
+
+    #[test]
+    fn relative_dates() {
+        use super::dates::resolve_relative_dates;
+
+        // A Wednesday
+        let today = NaiveDate::from_ymd(2023, 3, 15);
+        let resolve = |input: &str| resolve_relative_dates(input, today);
+
+        assert_eq!(resolve("today").unwrap(), "2023-03-15");
+        assert_eq!(
+            resolve("[2023-01-01:today]").unwrap(),
+            "[2023-01-01:2023-03-15]"
+        );
+        assert_eq!(resolve("last-7d").unwrap(), "[2023-03-08:2023-03-15]");
+        assert_eq!(resolve("last-2w").unwrap(), "[2023-03-01:2023-03-15]");
+        assert_eq!(resolve("last-1y").unwrap(), "[2022-03-15:2023-03-15]");
+        assert_eq!(resolve("this-week").unwrap(), "[2023-03-13:2023-03-15]");
+        assert_eq!(resolve("this-month").unwrap(), "[2023-03-01:2023-03-15]");
+        assert_eq!(resolve("this-year").unwrap(), "[2023-01-01:2023-03-15]");
+        assert_eq!(
+            resolve("{yesterday, 2020-01-01}").unwrap(),
+            "{2023-03-14,2020-01-01}"
+        );
+        assert_eq!(resolve("2020-01-01").unwrap(), "2020-01-01");
+
+        // Months are clamped to their last day
+        assert_eq!(
+            resolve_relative_dates("last-1m", NaiveDate::from_ymd(2023, 3, 31)).unwrap(),
+            "[2023-02-28:2023-03-31]"
+        );
+        // Periods starting today are a single day
+        assert_eq!(
+            resolve_relative_dates("this-year", NaiveDate::from_ymd(2023, 1, 1)).unwrap(),
+            "2023-01-01"
+        );
+
+        assert_eq!(
+            resolve("last-7x").err().unwrap(),
+            QueryError::InvalidRelativeDate("last-7x".to_owned())
+        );
+        assert_eq!(
+            resolve("this-decade").err().unwrap(),
+            QueryError::InvalidRelativeDate("this-decade".to_owned())
+        );
+        // Periods reaching out of the range of dates
+        for period in &[
+            "last-100000000d",
+            "last-4000000000w",
+            "last-4000000000m",
+            "last-400000000y",
+        ] {
+            assert_eq!(
+                resolve(period).err().unwrap(),
+                QueryError::InvalidRelativeDate((*period).to_owned())
+            );
+        }
+
+        assert_eq!(
+            Query::new("date: since-last-run").unwrap().op,
+            QueryOperationOptions::SinceLastRun
+        );
+        assert_eq!(
+            Query::new_at("date:[2023-01-01:today]", today).unwrap().op,
+            QueryOperationOptions::Date(RangeOrSet::Range(Range {
+                start: NaiveDate::from_ymd(2023, 1, 1),
+                end: Some(today)
+            }))
+        );
+        assert_eq!(
+            QueryExpr::new_at("title:a AND date:yesterday", today).unwrap(),
+            QueryExpr::And(
+                option("title:a"),
+                Box::new(QueryExpr::Option(Query {
+                    options: "date:yesterday",
+                    op: QueryOperationOptions::Date(RangeOrSet::Range(Range {
+                        start: NaiveDate::from_ymd(2023, 3, 14),
+                        end: None
+                    }))
+                }))
+            )
+        );
+    }
+
+    fn option(options: &str) -> Box<QueryExpr<'_>> {
+        Box::new(QueryExpr::Option(Query::new(options).unwrap()))
+    }
+
+    #[test]
+    fn expression_single_option() {
+        assert_eq!(
+            QueryExpr::new("title:cheese delight").unwrap(),
+            *option("title:cheese delight")
+        );
+        assert_eq!(
+            QueryExpr::new(" number:{15, [20:25]} ").unwrap(),
+            *option("number:{15, [20:25]}")
+        );
+    }
+
+    #[test]
+    fn expression_precedence() {
+        assert_eq!(
+            QueryExpr::new("title:interview AND NOT date:[2020-01-01:2020-12-31] OR number:{1,2}")
+                .unwrap(),
+            QueryExpr::Or(
+                Box::new(QueryExpr::And(
+                    option("title:interview"),
+                    Box::new(QueryExpr::Not(option("date:[2020-01-01:2020-12-31]")))
+                )),
+                option("number:{1,2}")
+            )
+        );
+        assert_eq!(
+            QueryExpr::new("title:a OR title:b AND title:c").unwrap(),
+            QueryExpr::Or(
+                option("title:a"),
+                Box::new(QueryExpr::And(option("title:b"), option("title:c")))
+            )
+        );
+        assert_eq!(
+            QueryExpr::new("NOT NOT latest").unwrap(),
+            QueryExpr::Not(Box::new(QueryExpr::Not(option("latest"))))
+        );
+    }
+
+    #[test]
+    fn expression_grouping() {
+        assert_eq!(
+            QueryExpr::new("(title:a OR title:b) AND NOT(number:[0:5])").unwrap(),
+            QueryExpr::And(
+                Box::new(QueryExpr::Or(option("title:a"), option("title:b"))),
+                Box::new(QueryExpr::Not(option("number:[0:5]")))
+            )
+        );
+        assert_eq!(QueryExpr::new("((title:a))").unwrap(), *option("title:a"));
+    }
+
+    #[test]
+    fn expression_parentheses_and_keywords_inside_options() {
+        assert_eq!(
+            QueryExpr::new("(title:Part (1) OR title:{ORANGE, AND})").unwrap(),
+            QueryExpr::Or(option("title:Part (1)"), option("title:{ORANGE, AND}"))
+        );
+        assert_eq!(
+            QueryExpr::new(r"(title~/\) AND \/c/i OR latest)").unwrap(),
+            QueryExpr::Or(option(r"title~/\) AND \/c/i"), option("latest"))
+        );
+    }
+
+    #[test]
+    fn expression_errors() {
+        assert_eq!(QueryExpr::new("  ").err().unwrap(), QueryError::EmptyQuery);
+        assert_eq!(
+            QueryExpr::new("title:a AND").err().unwrap(),
+            QueryError::MissingOperand("AND".to_owned())
+        );
+        assert_eq!(
+            QueryExpr::new("OR title:a").err().unwrap(),
+            QueryError::MissingOperand("".to_owned())
+        );
+        assert_eq!(
+            QueryExpr::new("(title:a").err().unwrap(),
+            QueryError::UnbalancedParentheses("( title:a".to_owned())
+        );
+        assert_eq!(
+            QueryExpr::new("title:a)").err().unwrap(),
+            QueryError::UnbalancedParentheses("title:a)".to_owned())
+        );
+        assert_eq!(
+            QueryExpr::new("(title:a) (title:b)").err().unwrap(),
+            QueryError::UnexpectedToken("(".to_owned())
+        );
+        assert_eq!(
+            QueryExpr::new("title:a OR number:[15]").err().unwrap(),
+            QueryError::Number(ParserError::MissingRangeDelimiter("[15]".to_owned()))
+        );
+    }
 }
@@ -1,20 +1,334 @@
+use super::dates::{resolve_relative_dates, SINCE_LAST_RUN};
 use super::error::{ParserError, QueryError};
 use super::parser::Parser;
 use super::rangeset::{Range, RangeOrSet};
-use super::QueryOp;
+use super::{QueryOp, RANGE_DELIMITER};
 use crate::feed::Feed;
-use crate::utils::create_file_path;
-use chrono::NaiveDate;
-use rss::Item;
-use std::convert::TryFrom;
+use crate::model::{Enclosure, Item};
+use crate::utils::{parse_duration, parse_size};
+use chrono::{NaiveDate, Utc};
+use regex::{Regex, RegexBuilder};
+
+/// Modifier of a text query matching regardless of capitalization, as in `title:ci:cheese`.
+const CASE_INSENSITIVE_MODIFIER: &str = "ci:";
+/// Modifier of a text query matching whole words only, as in `title:word:cheese`.
+const WHOLE_WORD_MODIFIER: &str = "word:";
+/// Flags accepted after a regular expression, as in `title~/^episode/i`.
+const REGEX_FLAGS: &str = "imsx";
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub(super) enum QueryOperationOptions {
     Date(RangeOrSet<NaiveDate>),
-    Title(RangeOrSet<String>),
-    Description(RangeOrSet<String>),
+    // Published after the last complete run into the output directory
+    SinceLastRun,
+    Title(TextMatcher),
+    Description(TextMatcher),
+    Field(ItemField, TextMatcher),
     Number(RangeOrSet<u64>),
+    // Length of the enclosure, in bytes
+    Size(RangeOrSet<u64>),
+    // Length of the episode, in seconds
+    Duration(RangeOrSet<u64>),
+    // Numbers the feed gives to the item, rather than its position
+    Season(RangeOrSet<u64>),
+    Episode(RangeOrSet<u64>),
+    Explicit(bool),
     NotExists,
+    New,
+}
+
+/// Text fields of an item that can be queried like the title, as in `category:Bonus`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(super) enum ItemField {
+    Author,
+    Category,
+    Guid,
+    Link,
+    EnclosureUrl,
+    EnclosureType,
+    Comments,
+    EpisodeType,
+}
+
+impl ItemField {
+    fn from_key(key: &str) -> Option<Self> {
+        match key {
+            "author" => Some(ItemField::Author),
+            "category" => Some(ItemField::Category),
+            "guid" => Some(ItemField::Guid),
+            "link" => Some(ItemField::Link),
+            "enclosure.url" => Some(ItemField::EnclosureUrl),
+            "enclosure.type" => Some(ItemField::EnclosureType),
+            "comments" => Some(ItemField::Comments),
+            "type" => Some(ItemField::EpisodeType),
+            _ => None,
+        }
+    }
+
+    /// The values of the field in `item`. Only categories may have more than one.
+    pub fn values<'item>(&self, item: &'item Item) -> Vec<&'item str> {
+        match self {
+            ItemField::Author => item.author().into_iter().collect(),
+            ItemField::Category => item.categories().iter().map(String::as_str).collect(),
+            ItemField::Guid => item.guid().into_iter().collect(),
+            ItemField::Link => item.link().into_iter().collect(),
+            ItemField::EnclosureUrl => item.enclosure().map(Enclosure::url).into_iter().collect(),
+            ItemField::EnclosureType => item
+                .enclosure()
+                .map(Enclosure::mime_type)
+                .into_iter()
+                .collect(),
+            ItemField::Comments => item.comments().into_iter().collect(),
+            ItemField::EpisodeType => vec![item.episode_type()],
+        }
+    }
+}
+
+/// How a text query matches the text of an item.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(super) enum TextMatcher {
+    /// The text contains one of the values.
+    Contains {
+        values: RangeOrSet<String>,
+        case_insensitive: bool,
+        whole_word: bool,
+    },
+    /// The text matches the regular expression, with the given flags.
+    Regex { pattern: String, flags: String },
+}
+
+impl TextMatcher {
+    /// Parse the value of a text query, `value` after a `:` or `pattern` after a `~`.
+    fn parse(value: &str, is_regex: bool) -> Result<Self, QueryError> {
+        if is_regex {
+            let value = value.trim();
+            let (pattern, flags) = match (value.strip_prefix('/'), value.rfind('/')) {
+                (Some(_), Some(end)) if end > 0 => (&value[1..end], &value[end + 1..]),
+                _ => return Err(QueryError::InvalidRegex(value.to_owned())),
+            };
+
+            if let Some(flag) = flags.chars().find(|flag| !REGEX_FLAGS.contains(*flag)) {
+                return Err(QueryError::InvalidRegex(format!(
+                    "unknown flag '{}' in {}",
+                    flag, value
+                )));
+            }
+
+            let matcher = TextMatcher::Regex {
+                pattern: pattern.to_owned(),
+                flags: flags.to_owned(),
+            };
+            // Report invalid expressions now rather than when the query runs
+            matcher.regex()?;
+
+            return Ok(matcher);
+        }
+
+        let mut value = value;
+        let mut case_insensitive = false;
+        let mut whole_word = false;
+
+        loop {
+            let trimmed = value.trim_start();
+            if let Some(rest) = trimmed.strip_prefix(CASE_INSENSITIVE_MODIFIER) {
+                case_insensitive = true;
+                value = rest;
+            } else if let Some(rest) = trimmed.strip_prefix(WHOLE_WORD_MODIFIER) {
+                whole_word = true;
+                value = rest;
+            } else {
+                break;
+            }
+        }
+
+        Ok(TextMatcher::Contains {
+            values: RangeOrSet::parse(value)?,
+            case_insensitive,
+            whole_word,
+        })
+    }
+
+    /// The regular expression equivalent to this matcher, for matchers that need one.
+    fn regex(&self) -> Result<Option<Regex>, QueryError> {
+        let regex = match self {
+            TextMatcher::Contains {
+                case_insensitive: false,
+                whole_word: false,
+                ..
+            } => return Ok(None),
+            TextMatcher::Contains {
+                values,
+                case_insensitive,
+                whole_word,
+            } => {
+                let alternatives = match values {
+                    RangeOrSet::Range(range) => regex::escape(&range.start),
+                    RangeOrSet::Set(set) => set
+                        .contents
+                        .iter()
+                        .map(|value| regex::escape(&value.start))
+                        .collect::<Vec<String>>()
+                        .join("|"),
+                };
+                let pattern = if *whole_word {
+                    format!(r"(?:^|\W)(?:{})(?:\W|$)", alternatives)
+                } else {
+                    alternatives
+                };
+
+                RegexBuilder::new(&pattern)
+                    .case_insensitive(*case_insensitive)
+                    .build()
+            }
+            // The '/' closing the expression is escaped inside it
+            TextMatcher::Regex { pattern, flags } => {
+                RegexBuilder::new(&pattern.replace(r"\/", "/"))
+                    .case_insensitive(flags.contains('i'))
+                    .multi_line(flags.contains('m'))
+                    .dot_matches_new_line(flags.contains('s'))
+                    .ignore_whitespace(flags.contains('x'))
+                    .build()
+            }
+        };
+
+        regex
+            .map(Some)
+            .map_err(|e| QueryError::InvalidRegex(e.to_string()))
+    }
+
+    /// Compile the matcher into a closure telling whether a text matches. Regular expressions
+    /// are compiled once, here.
+    pub fn build_func(self) -> Box<dyn Fn(&str) -> bool + Send + Sync> {
+        match self.regex().expect("expressions are checked when parsed") {
+            Some(regex) => Box::new(move |text: &str| -> bool { regex.is_match(text) }),
+            None => match self {
+                TextMatcher::Contains {
+                    values: RangeOrSet::Range(range),
+                    ..
+                } => Box::new(move |text: &str| -> bool { text.contains(&range.start) }),
+                TextMatcher::Contains {
+                    values: RangeOrSet::Set(set),
+                    ..
+                } => Box::new(move |text: &str| -> bool {
+                    set.contents.iter().any(|value| text.contains(&value.start))
+                }),
+                TextMatcher::Regex { .. } => unreachable!("regular expressions are compiled"),
+            },
+        }
+    }
+}
+
+/// Closure telling whether a number is in `ros`.
+fn build_number_func(ros: RangeOrSet<u64>) -> Box<dyn Fn(u64) -> bool + Send + Sync> {
+    match ros {
+        RangeOrSet::Range(range) => {
+            if let Some(end) = range.end {
+                Box::new(move |n: u64| -> bool { n >= range.start && n <= end })
+            } else {
+                Box::new(move |n: u64| -> bool { n == range.start })
+            }
+        }
+        RangeOrSet::Set(set) => Box::new(move |n: u64| -> bool {
+            for range in set.contents.iter() {
+                if let Some(end) = range.end {
+                    if n >= range.start && n <= end {
+                        return true;
+                    }
+                } else if n == range.start {
+                    return true;
+                }
+            }
+
+            false
+        }),
+    }
+}
+
+/// Parse a range or set of quantities written with units, like sizes or durations, into the
+/// plain numbers they stand for. Every quantity is converted with `parse_quantity` and the
+/// result goes through the same parser as numbers. `split_range` splits the inside of a range
+/// into its start and end.
+fn parse_quantities(
+    input: &str,
+    parse_quantity: fn(&str) -> Option<u64>,
+    split_range: fn(&str) -> Option<(&str, &str)>,
+) -> Result<RangeOrSet<u64>, QueryError> {
+    let convert = |quantity: &str| -> Result<String, QueryError> {
+        let quantity = quantity.trim();
+        if quantity.is_empty() {
+            // Let the number parser report the missing bound
+            return Ok(String::new());
+        }
+        parse_quantity(quantity)
+            .map(|n| n.to_string())
+            .ok_or_else(|| QueryError::InvalidQuantity(quantity.to_owned()))
+    };
+
+    let convert_element = |element: &str| -> Result<String, QueryError> {
+        let element = element.trim();
+        match element.strip_prefix('[').and_then(|e| e.strip_suffix(']')) {
+            Some(inside) => match split_range(inside) {
+                Some((start, end)) => Ok(format!(
+                    "[{}{}{}]",
+                    convert(start)?,
+                    RANGE_DELIMITER,
+                    convert(end)?
+                )),
+                None => Err(QueryError::Number(ParserError::MissingRangeDelimiter(
+                    element.to_owned(),
+                ))),
+            },
+            // Unfinished ranges are reported by the number parser
+            None if element.starts_with('[') || element.ends_with(']') => Ok(element.to_owned()),
+            None => convert(element),
+        }
+    };
+
+    Ok(RangeOrSet::parse(&rewrite_elements(
+        input,
+        convert_element,
+    )?)?)
+}
+
+/// Rewrite every scalar or range of a range or set with `convert_element`, keeping the set
+/// syntax around them.
+pub(super) fn rewrite_elements(
+    input: &str,
+    convert_element: impl Fn(&str) -> Result<String, QueryError>,
+) -> Result<String, QueryError> {
+    let input = input.trim();
+    match input.strip_prefix('{').and_then(|i| i.strip_suffix('}')) {
+        Some(inside) => Ok(format!(
+            "{{{}}}",
+            inside
+                .split(',')
+                .map(convert_element)
+                .collect::<Result<Vec<String>, QueryError>>()?
+                .join(",")
+        )),
+        None => convert_element(input),
+    }
+}
+
+/// Split the inside of a size range, "100MB:2GB".
+fn split_size_range(inside: &str) -> Option<(&str, &str)> {
+    inside.split_once(RANGE_DELIMITER)
+}
+
+/// Split the inside of a duration range. Durations may contain the range delimiter themselves,
+/// as in "00:30:00:01:30:00", so both ends are expected to be written alike and the range is
+/// split at the middle delimiter.
+fn split_duration_range(inside: &str) -> Option<(&str, &str)> {
+    let delimiters: Vec<usize> = inside
+        .match_indices(RANGE_DELIMITER)
+        .map(|(i, _)| i)
+        .collect();
+    if delimiters.len() % 2 == 0 {
+        return None;
+    }
+
+    let middle = delimiters[delimiters.len() / 2];
+    Some((&inside[..middle], &inside[middle + 1..]))
 }
 
 impl<'input> QueryOperationOptions {
@@ -47,143 +361,170 @@ impl<'input> QueryOperationOptions {
                 };
 
                 Box::new(move |(i, _, _): (&Item, usize, &Feed)| -> bool {
-                    let item_date = i.pub_date().unwrap();
-
-                    let date: NaiveDate = match chrono::DateTime::parse_from_rfc2822(item_date) {
-                        Ok(d) => d.date().naive_local(),
-                        Err(_) => {
-                            info!("Failed to parse item date. {}", item_date);
-                            return false;
-                        }
-                    };
-
-                    func(date)
+                    match i.pub_date() {
+                        Some(date) => func(date.naive_local().date()),
+                        None => false,
+                    }
                 })
             }
-            QueryOperationOptions::Number(ros) => {
-                let func: Box<dyn Fn(u64) -> bool + Send + Sync> = match ros {
-                    RangeOrSet::Range(range) => {
-                        if let Some(end) = range.end {
-                            Box::new(move |n: u64| -> bool { n >= range.start && n <= end })
-                        } else {
-                            Box::new(move |n: u64| -> bool { n == range.start })
-                        }
+            QueryOperationOptions::SinceLastRun => {
+                Box::new(|(i, _, feed): (&Item, usize, &Feed)| -> bool {
+                    match feed.last_run() {
+                        Some(last) => i
+                            .pub_date()
+                            .map_or(false, |date| date.with_timezone(&Utc) > last),
+                        None => true,
                     }
-                    RangeOrSet::Set(set) => Box::new(move |n: u64| -> bool {
-                        for range in set.contents.iter() {
-                            if let Some(end) = range.end {
-                                if n >= range.start && n <= end {
-                                    return true;
-                                }
-                            } else if n == range.start {
-                                return true;
-                            }
-                        }
-
-                        false
-                    }),
-                };
-
+                })
+            }
+            QueryOperationOptions::Number(ros) => {
+                let func = build_number_func(ros);
                 Box::new(move |(_, n, _): (&Item, usize, &Feed)| -> bool { func(n as u64) })
             }
-            QueryOperationOptions::Title(ros) => {
+            QueryOperationOptions::Size(ros) => {
+                let func = build_number_func(ros);
                 Box::new(move |(i, _, _): (&Item, usize, &Feed)| -> bool {
-                    match &ros {
-                        RangeOrSet::Range(range) => {
-                            if i.title().unwrap().contains(&range.start) {
-                                return true;
-                            }
-                        }
-                        RangeOrSet::Set(set) => {
-                            for value in set.contents.iter() {
-                                if i.title().unwrap().contains(&value.start) {
-                                    return true;
-                                }
-                            }
-                        }
-                    }
-
-                    false
+                    i.enclosure()
+                        .and_then(Enclosure::length)
+                        .map_or(false, &func)
                 })
             }
-            QueryOperationOptions::Description(ros) => {
+            QueryOperationOptions::Duration(ros) => {
+                let func = build_number_func(ros);
                 Box::new(move |(i, _, _): (&Item, usize, &Feed)| -> bool {
-                    match &ros {
-                        RangeOrSet::Range(range) => {
-                            if i.description().unwrap().contains(&range.start) {
-                                return true;
-                            }
-                        }
-                        RangeOrSet::Set(set) => {
-                            for value in set.contents.iter() {
-                                if i.description().unwrap().contains(&value.start) {
-                                    return true;
-                                }
-                            }
-                        }
-                    }
-
-                    false
+                    i.duration().map_or(false, &func)
+                })
+            }
+            QueryOperationOptions::Title(matcher) => {
+                let func = matcher.build_func();
+                Box::new(move |(i, _, _): (&Item, usize, &Feed)| -> bool {
+                    i.title().map_or(false, &func)
+                })
+            }
+            QueryOperationOptions::Description(matcher) => {
+                let func = matcher.build_func();
+                Box::new(move |(i, _, _): (&Item, usize, &Feed)| -> bool {
+                    i.description().map_or(false, &func)
+                })
+            }
+            QueryOperationOptions::Field(field, matcher) => {
+                let func = matcher.build_func();
+                Box::new(move |(i, _, _): (&Item, usize, &Feed)| -> bool {
+                    field.values(i).into_iter().any(&func)
+                })
+            }
+            QueryOperationOptions::Season(ros) => {
+                let func = build_number_func(ros);
+                Box::new(move |(i, _, _): (&Item, usize, &Feed)| -> bool {
+                    i.season().map_or(false, &func)
+                })
+            }
+            QueryOperationOptions::Episode(ros) => {
+                let func = build_number_func(ros);
+                Box::new(move |(i, _, _): (&Item, usize, &Feed)| -> bool {
+                    i.episode().map_or(false, &func)
+                })
+            }
+            QueryOperationOptions::Explicit(explicit) => {
+                Box::new(move |(i, _, _): (&Item, usize, &Feed)| -> bool {
+                    i.explicit() == Some(explicit)
                 })
             }
             QueryOperationOptions::NotExists => {
                 Box::new(|(i, _, feed): (&Item, usize, &Feed)| -> bool {
-                    let new_file = create_file_path(
-                        feed.get_config_output(),
-                        i.enclosure().unwrap().mime_type(),
-                        i.title().unwrap(),
-                    );
-
-                    !new_file.exists()
+                    !feed.file_path(i).exists()
                 })
             }
+            QueryOperationOptions::New => {
+                Box::new(|(i, _, feed): (&Item, usize, &Feed)| -> bool { !feed.is_downloaded(i) })
+            }
         };
 
         func
     }
 }
 
-impl<'input> TryFrom<&'input str> for QueryOperationOptions {
-    type Error = QueryError;
+impl QueryOperationOptions {
+    /// Parse a query option. Relative dates are resolved against `today`.
+    pub fn parse(options: &str, today: NaiveDate) -> Result<QueryOperationOptions, QueryError> {
+        // Options are of the form "key", "key:value" or "key~/pattern/flags"
+        let (key, value, is_regex) = match options.find([':', '~']) {
+            Some(i) => (
+                options[..i].trim(),
+                Some(&options[i + 1..]),
+                options[i..].starts_with('~'),
+            ),
+            None => (options.trim(), None, false),
+        };
+
+        if let (Some(field), Some(value)) = (ItemField::from_key(key), value) {
+            return Ok(QueryOperationOptions::Field(
+                field,
+                TextMatcher::parse(value, is_regex)?,
+            ));
+        }
 
-    fn try_from(options: &'input str) -> Result<QueryOperationOptions, Self::Error> {
-        match options {
-            _ if options.starts_with("number:") => {
-                let range_or_set = RangeOrSet::parse(&options[7..])?;
+        match (key, value) {
+            ("title", Some(value)) => Ok(QueryOperationOptions::Title(TextMatcher::parse(
+                value, is_regex,
+            )?)),
+            ("description", Some(value)) => Ok(QueryOperationOptions::Description(
+                TextMatcher::parse(value, is_regex)?,
+            )),
+            _ if is_regex => Err(QueryError::InvalidQueryOption(options.to_string())),
+            ("number", Some(value)) => {
+                let range_or_set = RangeOrSet::parse(value)?;
                 Ok(QueryOperationOptions::Number(range_or_set))
             }
-            _ if options.starts_with("title:") => {
-                let range_or_set = RangeOrSet::parse(&options[6..])?;
-                Ok(QueryOperationOptions::Title(range_or_set))
+            ("date", Some(value)) if value.trim() == SINCE_LAST_RUN => {
+                Ok(QueryOperationOptions::SinceLastRun)
             }
-            _ if options.starts_with("description:") => {
-                let range_or_set = RangeOrSet::parse(&options[12..])?;
-                Ok(QueryOperationOptions::Description(range_or_set))
-            }
-            _ if options.starts_with("date:") => {
-                let range_or_set = RangeOrSet::parse(&options[5..])?;
+            ("date", Some(value)) => {
+                let value = resolve_relative_dates(value, today)?;
+                let range_or_set = RangeOrSet::parse(&value)?;
                 Ok(QueryOperationOptions::Date(range_or_set))
             }
-            "notexists" => Ok(QueryOperationOptions::NotExists),
-            _ if options.starts_with("latest") => {
-                let maybe_end_str = &options[6..].trim();
+            ("size", Some(value)) => Ok(QueryOperationOptions::Size(parse_quantities(
+                value,
+                parse_size,
+                split_size_range,
+            )?)),
+            ("duration", Some(value)) => Ok(QueryOperationOptions::Duration(parse_quantities(
+                value,
+                parse_duration,
+                split_duration_range,
+            )?)),
+            ("season", Some(value)) => {
+                let range_or_set = RangeOrSet::parse(value)?;
+                Ok(QueryOperationOptions::Season(range_or_set))
+            }
+            ("episode", Some(value)) => {
+                let range_or_set = RangeOrSet::parse(value)?;
+                Ok(QueryOperationOptions::Episode(range_or_set))
+            }
+            ("explicit", None) => Ok(QueryOperationOptions::Explicit(true)),
+            ("explicit", Some(value)) => match value.trim() {
+                "yes" | "true" => Ok(QueryOperationOptions::Explicit(true)),
+                "no" | "false" => Ok(QueryOperationOptions::Explicit(false)),
+                _ => Err(QueryError::InvalidQueryOption(options.to_string())),
+            },
+            ("notexists", None) => Ok(QueryOperationOptions::NotExists),
+            ("new", None) => Ok(QueryOperationOptions::New),
+            ("latest", maybe_end_str) => {
+                let end = if let Some(end_str) = maybe_end_str {
+                    let end_str = end_str.trim();
 
-                let end = if maybe_end_str.is_empty() {
-                    None
-                } else if maybe_end_str.contains(':') {
-                    let new_maybe_end_str = &maybe_end_str[1..].trim();
-
-                    if !new_maybe_end_str.is_empty() {
-                        Some(
-                            new_maybe_end_str
-                                .parse::<u64>()
-                                .map_err(|e| QueryError::Number(ParserError::ParseInt(e)))?,
-                        )
-                    } else {
+                    if end_str.is_empty() {
                         return Err(QueryError::Number(ParserError::EmptyInput));
                     }
+
+                    Some(
+                        end_str
+                            .parse::<u64>()
+                            .map_err(|e| QueryError::Number(ParserError::ParseInt(e)))?,
+                    )
                 } else {
-                    return Err(QueryError::InvalidQueryOption(options.to_string()));
+                    None
                 };
 
                 Ok(QueryOperationOptions::Number(RangeOrSet::Range(Range {
@@ -178,6 +178,7 @@ impl Parser<u64> for RangeOrSet<u64> {
         Ok(RangeOrSet::Range(Range { start, end }))
     }
 
+    #[allow(clippy::unnecessary_unwrap)]
     fn parse_set(input: &str) -> Result<RangeOrSet<u64>, ParserError<u64>> {
         // Set construction
         let str_trimmed = input.trim_end_matches('}').trim_start_matches('{');
@@ -372,6 +373,7 @@ impl Parser<NaiveDate> for RangeOrSet<NaiveDate> {
         Ok(RangeOrSet::Range(Range { start, end }))
     }
 
+    #[allow(clippy::unnecessary_unwrap)]
     fn parse_set(input: &str) -> Result<RangeOrSet<NaiveDate>, ParserError<NaiveDate>> {
         // Set construction
         let str_trimmed = input.trim_end_matches('}').trim_start_matches('{');
@@ -0,0 +1,250 @@
+use super::utils::random_duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+use std::io;
+use std::time::Duration;
+
+/// How failed requests are retried: up to `max_attempts` attempts, waiting an exponentially
+/// growing delay between them, from `base_delay` up to `max_delay`. With jitter, each delay
+/// is a random duration between half and all of it, so concurrent downloads don't retry in
+/// lockstep.
+///
+/// Servers answering 429 or 503 with a `Retry-After` header are waited for as long as they
+/// ask, up to `max_delay`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    retryable_statuses: Vec<StatusCode>,
+    retryable_io_errors: Vec<io::ErrorKind>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: true,
+            retryable_statuses: vec![
+                StatusCode::REQUEST_TIMEOUT,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+            retryable_io_errors: vec![
+                io::ErrorKind::ConnectionRefused,
+                io::ErrorKind::ConnectionReset,
+                io::ErrorKind::ConnectionAborted,
+                io::ErrorKind::BrokenPipe,
+                io::ErrorKind::TimedOut,
+                io::ErrorKind::Interrupted,
+                io::ErrorKind::UnexpectedEof,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Make at most `max_attempts` attempts, including the first one.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Retry responses with one of `retryable_statuses`.
+    pub fn with_retryable_statuses(mut self, retryable_statuses: Vec<StatusCode>) -> Self {
+        self.retryable_statuses = retryable_statuses;
+        self
+    }
+
+    /// Retry requests failing with one of `retryable_io_errors`.
+    pub fn with_retryable_io_errors(mut self, retryable_io_errors: Vec<io::ErrorKind>) -> Self {
+        self.retryable_io_errors = retryable_io_errors;
+        self
+    }
+
+    pub fn get_max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Time to wait after the failed attempt number `attempt`, starting at 1. `retry_after`
+    /// is the delay the server asked for, if it did.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_delay);
+        }
+
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+
+        if self.jitter {
+            delay / 2 + random_duration(delay / 2)
+        } else {
+            delay
+        }
+    }
+
+    pub fn is_retryable_status(&self, status: StatusCode) -> bool {
+        self.retryable_statuses.contains(&status)
+    }
+
+    /// Whether `error` is a transient failure, like a timeout or a dropped connection.
+    pub fn is_retryable_error(&self, error: &reqwest::Error) -> bool {
+        if error.is_timeout() || error.is_connect() {
+            return true;
+        }
+
+        // Look for the I/O error behind the request error
+        let mut source = std::error::Error::source(error);
+        while let Some(e) = source {
+            if let Some(io_error) = e.downcast_ref::<io::Error>() {
+                return self.retryable_io_errors.contains(&io_error.kind());
+            }
+            source = e.source();
+        }
+
+        false
+    }
+
+    /// Send `request`, retrying transient errors and retryable responses. The last response
+    /// is returned even if its status is retryable, so the caller can report it.
+    pub async fn send(&self, mut request: RequestBuilder) -> Result<Response, reqwest::Error> {
+        let mut attempt = 1;
+
+        loop {
+            // Requests with a streamed body can't be sent again
+            let next = match request.try_clone() {
+                Some(next) if attempt < self.max_attempts => next,
+                _ => return request.send().await,
+            };
+
+            let result = request.send().await;
+            let retry_after = match &result {
+                Ok(response) if self.is_retryable_status(response.status()) => {
+                    info!(
+                        "{} answered {}. Retrying",
+                        response.url(),
+                        response.status()
+                    );
+                    retry_after(response)
+                }
+                Err(e) if self.is_retryable_error(e) => {
+                    info!("Request failed: {}. Retrying", e);
+                    None
+                }
+                _ => return result,
+            };
+
+            tokio::time::sleep(self.delay(attempt, retry_after)).await;
+            request = next;
+            attempt += 1;
+        }
+    }
+}
+
+/// Delay asked for by a 429 or 503 response.
+fn retry_after(response: &Response) -> Option<Duration> {
+    if !matches!(
+        response.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return None;
+    }
+
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, Utc::now())
+}
+
+/// `Retry-After` is either a number of seconds or the date to retry at.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+
+    match value.parse() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = DateTime::parse_from_rfc2822(value).ok()?;
+            // Dates in the past mean right away
+            Some(
+                (date.with_timezone(&Utc) - now)
+                    .to_std()
+                    .unwrap_or_default(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_delays() {
+        let policy = RetryPolicy::default()
+            .with_base_delay(Duration::from_secs(1))
+            .with_max_delay(Duration::from_secs(10))
+            .with_jitter(false);
+
+        assert_eq!(policy.delay(1, None), Duration::from_secs(1));
+        assert_eq!(policy.delay(3, None), Duration::from_secs(4));
+        assert_eq!(policy.delay(5, None), Duration::from_secs(10));
+        assert_eq!(policy.delay(100, None), Duration::from_secs(10));
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(7))),
+            Duration::from_secs(7)
+        );
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(70))),
+            Duration::from_secs(10)
+        );
+
+        let delay = policy.with_jitter(true).delay(3, None);
+        assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+    }
+
+    #[test]
+    fn parse_retry_after_values() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:29:30 GMT", now),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
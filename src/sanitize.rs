@@ -0,0 +1,226 @@
+use super::error::RssDumpError;
+use super::utils::{PART_EXTENSION, SEGMENTS_EXTENSION};
+
+use sha2::{Digest, Sha256};
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Longest file or directory name, in bytes, accepted by the common filesystems.
+const MAX_NAME_BYTES: usize = 255;
+
+/// Longest extension kept apart from the rest of the name when truncating.
+const MAX_EXTENSION_BYTES: usize = 16;
+
+/// Names Windows reserves for devices, with or without an extension.
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Which filesystems the names of downloaded files must be valid in.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum SanitizePolicy {
+    /// Valid everywhere: the Windows rules, without hidden files or names starting with `-`.
+    #[default]
+    Portable,
+    /// Valid in FAT and NTFS: no `<>:"/\|?*` or control characters, no trailing dots or
+    /// spaces and no reserved device names.
+    Windows,
+    /// Valid in POSIX filesystems: only `/` and NUL are replaced.
+    Posix,
+}
+
+impl FromStr for SanitizePolicy {
+    type Err = Box<RssDumpError>;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "portable" => Ok(SanitizePolicy::Portable),
+            "windows" => Ok(SanitizePolicy::Windows),
+            "posix" => Ok(SanitizePolicy::Posix),
+            _ => Err(Box::new(RssDumpError::UnknownSanitizePolicy(
+                policy.to_owned(),
+            ))),
+        }
+    }
+}
+
+impl SanitizePolicy {
+    /// Make every component of the relative path `path` valid under this policy. `suffix` is
+    /// appended to the file name, before its extension, and survives truncation.
+    pub fn sanitize_path(self, path: &Path, suffix: &str) -> PathBuf {
+        let components: Vec<String> = path
+            .iter()
+            .map(|component| component.to_string_lossy().into_owned())
+            .collect();
+
+        match components.split_last() {
+            Some((file_name, dirs)) => dirs
+                .iter()
+                .map(|dir| self.sanitize_name(dir, "", MAX_NAME_BYTES))
+                .chain(std::iter::once(self.sanitize_name(
+                    file_name,
+                    suffix,
+                    // Leave room for the extensions of partial downloads and of the file
+                    // tracking their segments, as in `.part.segments`
+                    MAX_NAME_BYTES - PART_EXTENSION.len() - SEGMENTS_EXTENSION.len() - 2,
+                )))
+                .collect(),
+            None => PathBuf::from(self.sanitize_name("", suffix, MAX_NAME_BYTES)),
+        }
+    }
+
+    /// Key under which two paths name the same file. FAT and NTFS ignore case.
+    pub fn collision_key(self, path: &Path) -> String {
+        match self {
+            SanitizePolicy::Posix => path.to_string_lossy().into_owned(),
+            SanitizePolicy::Portable | SanitizePolicy::Windows => {
+                path.to_string_lossy().to_lowercase()
+            }
+        }
+    }
+
+    fn sanitize_name(self, name: &str, suffix: &str, max_bytes: usize) -> String {
+        let (stem, extension) = split_extension(name);
+        let stem = self.replace_invalid(stem);
+        let extension = self.replace_invalid(extension);
+        let suffix = self.replace_invalid(suffix);
+
+        let extension = if extension.is_empty() {
+            String::new()
+        } else {
+            format!(".{}", extension)
+        };
+        let budget = max_bytes.saturating_sub(suffix.len() + extension.len() + 1);
+        let mut name = truncate(&stem, budget).to_owned();
+        name.push_str(&suffix);
+
+        if self == SanitizePolicy::Portable {
+            name = name.trim_start_matches(&['.', '-', ' '][..]).to_owned();
+        }
+
+        if self != SanitizePolicy::Posix && is_windows_reserved(&name) {
+            name.insert(0, '_');
+        }
+
+        if self != SanitizePolicy::Posix {
+            // Windows drops trailing dots and spaces, so two names could end up the same
+            name.truncate(name.trim_end_matches(&['.', ' '][..]).len());
+        }
+
+        if name.is_empty() || name == "." || name == ".." {
+            name = String::from("_");
+        }
+
+        name + &extension
+    }
+
+    fn replace_invalid(self, name: &str) -> String {
+        name.chars()
+            .map(|c| match self {
+                SanitizePolicy::Posix if c == '/' || c == '\0' => '-',
+                SanitizePolicy::Portable | SanitizePolicy::Windows
+                    if c.is_control() || "<>:\"/\\|?*".contains(c) =>
+                {
+                    '-'
+                }
+                _ => c,
+            })
+            .collect()
+    }
+}
+
+/// Suffix telling apart an item named like another one: a short hash of its key, e.g. its
+/// GUID, or its number when it has none.
+pub fn collision_suffix(key: Option<&str>, number: usize) -> String {
+    match key {
+        Some(key) => format!(" [{:.8x}]", Sha256::digest(key.as_bytes())),
+        None => format!(" [{}]", number),
+    }
+}
+
+/// Split `name` into its stem and extension. Long or blank "extensions" are part of the stem.
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i)
+            if i > 0
+                && name.len() - i - 1 <= MAX_EXTENSION_BYTES
+                && !name[i + 1..].is_empty()
+                && !name[i + 1..].contains(' ') =>
+        {
+            (&name[..i], &name[i + 1..])
+        }
+        _ => (name, ""),
+    }
+}
+
+fn is_windows_reserved(stem: &str) -> bool {
+    let device = stem.split('.').next().unwrap_or_default().trim_end();
+    WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(device))
+}
+
+/// Longest prefix of `s` with at most `max_bytes` bytes that doesn't split a character.
+fn truncate(s: &str, max_bytes: usize) -> &str {
+    if s.len() <= max_bytes {
+        return s;
+    }
+
+    let mut end = max_bytes;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{create_part_file_path, create_segments_file_path};
+
+    #[test]
+    fn sanitize_names() {
+        let path = Path::new("Cheese: Talk?/.Brie <live>. .mp3");
+
+        assert_eq!(
+            SanitizePolicy::Portable.sanitize_path(path, ""),
+            PathBuf::from("Cheese- Talk-/Brie -live-.mp3")
+        );
+        assert_eq!(
+            SanitizePolicy::Windows.sanitize_path(path, ""),
+            PathBuf::from("Cheese- Talk-/.Brie -live-.mp3")
+        );
+        assert_eq!(
+            SanitizePolicy::Posix.sanitize_path(path, ""),
+            PathBuf::from("Cheese: Talk?/.Brie <live>. .mp3")
+        );
+        assert_eq!(
+            SanitizePolicy::Windows.sanitize_path(Path::new("con.mp3"), ""),
+            PathBuf::from("_con.mp3")
+        );
+        assert_eq!(
+            SanitizePolicy::Portable.sanitize_path(Path::new("..."), " [1]"),
+            PathBuf::from("[1]")
+        );
+        assert!("posixx".parse::<SanitizePolicy>().is_err());
+    }
+
+    #[test]
+    fn truncate_long_names() {
+        let long_title = "é".repeat(200);
+        let path = PathBuf::from(&long_title).join(format!("{}.mp3", long_title));
+
+        let sanitized = SanitizePolicy::Portable.sanitize_path(&path, " [cafe]");
+        let dir = sanitized.parent().unwrap().to_str().unwrap();
+        let file_name = sanitized.file_name().unwrap().to_str().unwrap();
+
+        assert_eq!(dir.len(), 254);
+        assert!(file_name.ends_with("é [cafe].mp3"));
+
+        // Even the file tracking the segments of its partial download has a valid name
+        let segments_file = create_segments_file_path(&create_part_file_path(Path::new(file_name)));
+        assert!(segments_file.as_os_str().len() <= MAX_NAME_BYTES);
+    }
+}
@@ -0,0 +1,204 @@
+use super::error::RssDumpError;
+use super::feed::Feed;
+use super::model::Item;
+
+use futures::stream::{self, StreamExt};
+
+use std::os::unix::fs::MetadataExt;
+use std::str::FromStr;
+use std::sync::Weak;
+
+/// Enclosures whose size is asked to the server at once, when the feed doesn't tell it.
+const CONCURRENT_PROBES: usize = 8;
+
+/// What to do when the selected items don't fit in the free space of the output filesystem.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum SpacePolicy {
+    /// Download nothing and fail with [`RssDumpError::NotEnoughFreeSpace`].
+    #[default]
+    Fail,
+    /// Skip the items that don't fit, in download order.
+    Trim,
+    /// Download everything anyway.
+    Ignore,
+}
+
+impl FromStr for SpacePolicy {
+    type Err = Box<RssDumpError>;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "fail" => Ok(SpacePolicy::Fail),
+            "trim" => Ok(SpacePolicy::Trim),
+            "ignore" => Ok(SpacePolicy::Ignore),
+            _ => Err(Box::new(RssDumpError::UnknownSpacePolicy(
+                policy.to_owned(),
+            ))),
+        }
+    }
+}
+
+/// How the free space is checked before downloading: what to do when the downloads don't fit
+/// and how many bytes to keep free after them.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SpaceCheck {
+    policy: SpacePolicy,
+    reserve: u64,
+}
+
+impl Default for SpaceCheck {
+    fn default() -> Self {
+        Self {
+            policy: SpacePolicy::default(),
+            reserve: 100 * 1024 * 1024,
+        }
+    }
+}
+
+impl SpaceCheck {
+    pub fn with_policy(mut self, policy: SpacePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Keep `reserve` bytes free after every download.
+    pub fn with_reserve(mut self, reserve: u64) -> Self {
+        self.reserve = reserve;
+        self
+    }
+
+    pub fn get_policy(&self) -> SpacePolicy {
+        self.policy
+    }
+
+    pub fn get_reserve(&self) -> u64 {
+        self.reserve
+    }
+}
+
+/// Check that the items of `download_lists`, one list per feed, fit in the free space of the
+/// filesystem of their output directory, following the space check of each feed. Feeds
+/// downloading into the same filesystem are checked together. Output directories must exist.
+///
+/// The size of an item is the length of its enclosure, asked to the server when the feed
+/// doesn't tell it, minus what a previous run left in its partial file. Items of unknown
+/// size are assumed to fit.
+pub async fn check_free_space(
+    feeds: &[&Feed<'_>],
+    download_lists: &mut [Vec<Weak<Item>>],
+) -> Result<(), Box<RssDumpError>> {
+    // Feeds grouped by the device of their output directory
+    let mut filesystems: Vec<(u64, Vec<usize>)> = vec![];
+    for (i, feed) in feeds.iter().enumerate() {
+        let device = std::fs::metadata(feed.get_config().get_output_dir())?.dev();
+        match filesystems.iter_mut().find(|(other, _)| *other == device) {
+            Some((_, group)) => group.push(i),
+            None => filesystems.push((device, vec![i])),
+        }
+    }
+
+    for (_, group) in filesystems {
+        let first = feeds[group[0]];
+        let check = first.get_config().get_space_check();
+        if check.policy == SpacePolicy::Ignore {
+            continue;
+        }
+
+        // Items in the order they are downloaded, oldest first
+        let items: Vec<(usize, &Weak<Item>)> = group
+            .iter()
+            .flat_map(|&i| download_lists[i].iter().rev().map(move |item| (i, item)))
+            .collect();
+        if items.is_empty() {
+            continue;
+        }
+
+        let sizes: Vec<u64> = stream::iter(&items)
+            .map(|(i, item)| async move {
+                match item.upgrade() {
+                    Some(item) => feeds[*i].remaining_size(&item).await.unwrap_or(0),
+                    None => 0,
+                }
+            })
+            .buffered(CONCURRENT_PROBES)
+            .collect()
+            .await;
+
+        let output_dir = first.get_config().get_output_dir();
+        let available = fs2::available_space(output_dir)?;
+        let required = sizes.iter().sum::<u64>() + check.reserve;
+        info!(
+            "Downloads into {} need {} bytes, {} are available",
+            output_dir.display(),
+            required,
+            available
+        );
+        if required <= available {
+            continue;
+        }
+
+        match check.policy {
+            SpacePolicy::Fail => {
+                return Err(Box::new(RssDumpError::NotEnoughFreeSpace {
+                    required,
+                    available,
+                }))
+            }
+            SpacePolicy::Trim => {
+                let fits = fitting(&sizes, available.saturating_sub(check.reserve));
+                let skipped = fits.iter().filter(|fits| !**fits).count();
+                println!(
+                    "Skipping {} items that don't fit in the free space of {}",
+                    skipped,
+                    output_dir.display()
+                );
+
+                let mut fits = fits.into_iter();
+                for &i in &group {
+                    // Lists are newest first, while sizes are in download order
+                    let mut kept: Vec<Weak<Item>> = download_lists[i]
+                        .drain(..)
+                        .rev()
+                        .filter(|_| fits.next().unwrap_or(false))
+                        .collect();
+                    kept.reverse();
+                    download_lists[i] = kept;
+                }
+            }
+            SpacePolicy::Ignore => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Which of the downloads of `sizes`, in order, fit in `available` bytes. Downloads that
+/// don't fit are skipped, and the following ones may still fit.
+fn fitting(sizes: &[u64], mut available: u64) -> Vec<bool> {
+    sizes
+        .iter()
+        .map(|size| {
+            let fits = *size <= available;
+            if fits {
+                available -= size;
+            }
+            fits
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_downloads_in_order() {
+        assert_eq!(
+            fitting(&[40, 30, 50, 20, 0], 100),
+            [true, true, false, true, true]
+        );
+        assert_eq!(fitting(&[10, 10], 0), [false, false]);
+        assert_eq!("trim".parse::<SpacePolicy>().unwrap(), SpacePolicy::Trim);
+        assert!("never".parse::<SpacePolicy>().is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use super::error::RssDumpError;
+use super::model::{Enclosure, Item};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the file, inside the output directory, where the download state is kept.
+pub const STATE_FILE_NAME: &str = ".dumptruckrss-state.json";
+
+/// Everything we know about an item that was successfully downloaded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ItemRecord {
+    pub guid: String,
+    pub url: String,
+    /// Location of the file, relative to the output directory.
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    pub downloaded_at: DateTime<Utc>,
+}
+
+/// Persistent record of the items downloaded into an output directory, keyed by item GUID.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DownloadState {
+    items: HashMap<String, ItemRecord>,
+    /// When the feed was fetched by the last run that downloaded every item it selected.
+    #[serde(default)]
+    last_run: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl DownloadState {
+    /// Load the state stored in `output_dir`. A missing state file yields an empty state.
+    pub fn load(output_dir: &Path) -> Result<Self, Box<RssDumpError>> {
+        let path = output_dir.join(STATE_FILE_NAME);
+
+        let mut state: Self = if path.is_file() {
+            info!("Loading download state from {}", path.display());
+            serde_json::from_slice(&fs::read(&path)?)?
+        } else {
+            Self::default()
+        };
+        state.path = path;
+
+        Ok(state)
+    }
+
+    /// Write the state to disk. The state is written to a temporary file first so that an
+    /// interrupted save never leaves a truncated state behind.
+    pub fn save(&self) -> Result<(), Box<RssDumpError>> {
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        Ok(())
+    }
+
+    pub fn contains(&self, guid: &str) -> bool {
+        self.items.contains_key(guid)
+    }
+
+    pub fn get(&self, guid: &str) -> Option<&ItemRecord> {
+        self.items.get(guid)
+    }
+
+    pub fn insert(&mut self, record: ItemRecord) {
+        self.items.insert(record.guid.clone(), record);
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &ItemRecord> {
+        self.items.values()
+    }
+
+    pub fn last_run(&self) -> Option<DateTime<Utc>> {
+        self.last_run
+    }
+
+    pub fn set_last_run(&mut self, fetched_at: DateTime<Utc>) {
+        self.last_run = Some(fetched_at);
+    }
+}
+
+/// Key under which an item is stored. Items without a GUID fall back to their enclosure URL.
+pub fn item_key(item: &Item) -> Option<&str> {
+    item.guid().or_else(|| item.enclosure().map(Enclosure::url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn save_and_load() {
+        let temp_dir = TempDir::new("state");
+        let dir = temp_dir.path();
+
+        // A missing state file is an empty state
+        let mut state = DownloadState::load(dir).unwrap();
+        assert_eq!(state.records().count(), 0);
+        assert_eq!(state.last_run(), None);
+
+        let record = ItemRecord {
+            guid: String::from("urn:cheese"),
+            url: String::from("http://example.com/cheese.mp3"),
+            path: PathBuf::from("Cheese.mp3"),
+            size: 6,
+            sha256: String::from("abc"),
+            downloaded_at: Utc::now(),
+        };
+        let last_run = Utc::now();
+        state.insert(record.clone());
+        state.set_last_run(last_run);
+        state.save().unwrap();
+        assert!(!dir.join(format!("{}.tmp", STATE_FILE_NAME)).exists());
+
+        let loaded = DownloadState::load(dir).unwrap();
+        assert!(loaded.contains("urn:cheese"));
+        assert_eq!(loaded.get("urn:cheese"), Some(&record));
+        assert_eq!(loaded.last_run(), Some(last_run));
+
+        // A corrupt state file is an error, rather than forgetting every download
+        fs::write(dir.join(STATE_FILE_NAME), b"{\"items\": {").unwrap();
+        assert!(DownloadState::load(dir).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use super::error::RssDumpError;
+use super::sanitize::SanitizePolicy;
+use super::utils::config_dir;
+
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the file, inside the configuration directory, where subscriptions are kept.
+pub const SUBSCRIPTIONS_FILE_NAME: &str = "subscriptions.json";
+
+// Non standard outline attributes used to carry our settings through an OPML round trip
+const OUTPUT_ATTRIBUTE: &[u8] = b"dumptruckrssOutput";
+const QUERY_ATTRIBUTE: &[u8] = b"dumptruckrssQuery";
+
+/// A feed we are subscribed to, together with where and what to download from it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subscription {
+    pub title: String,
+    pub url: String,
+    pub output: PathBuf,
+    pub query: String,
+    /// How often `watch` polls the feed, e.g. "30m" or "1h 30m".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<String>,
+}
+
+/// Persistent list of subscriptions, keyed by feed URL.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Subscriptions {
+    subscriptions: Vec<Subscription>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Subscriptions {
+    /// Default location of the subscriptions file.
+    pub fn default_path() -> PathBuf {
+        config_dir().join(SUBSCRIPTIONS_FILE_NAME)
+    }
+
+    /// Load the subscriptions stored in `path`. A missing file yields no subscriptions.
+    pub fn load(path: &Path) -> Result<Self, Box<RssDumpError>> {
+        let mut subscriptions: Self = if path.is_file() {
+            info!("Loading subscriptions from {}", path.display());
+            serde_json::from_slice(&fs::read(path)?)?
+        } else {
+            Self::default()
+        };
+        subscriptions.path = path.to_path_buf();
+
+        Ok(subscriptions)
+    }
+
+    /// Write the subscriptions to disk, creating the configuration directory if necessary.
+    pub fn save(&self) -> Result<(), Box<RssDumpError>> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        Ok(())
+    }
+
+    /// Add a subscription, replacing any existing subscription to the same feed.
+    pub fn add(&mut self, subscription: Subscription) {
+        match self
+            .subscriptions
+            .iter_mut()
+            .find(|existing| existing.url == subscription.url)
+        {
+            Some(existing) => *existing = subscription,
+            None => self.subscriptions.push(subscription),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Subscription> {
+        self.subscriptions.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.is_empty()
+    }
+
+    /// Add every feed in an OPML document. Feeds without an output directory of their own are
+    /// stored in a directory named after them inside `base_output`, and feeds without a query
+    /// get `query`. Returns the number of feeds imported.
+    pub fn import_opml(
+        &mut self,
+        content: &[u8],
+        base_output: &Path,
+        query: &str,
+    ) -> Result<usize, Box<RssDumpError>> {
+        let mut reader = Reader::from_reader(content);
+        reader.trim_text(true);
+
+        let mut buf = vec![];
+        let mut imported = 0;
+
+        loop {
+            match reader.read_event(&mut buf)? {
+                Event::Start(ref element) | Event::Empty(ref element)
+                    if element.local_name() == b"outline" =>
+                {
+                    if let Some(subscription) = read_outline(&reader, element, base_output, query)?
+                    {
+                        self.add(subscription);
+                        imported += 1;
+                    }
+                }
+                Event::Eof => break,
+                _ => (),
+            }
+            buf.clear();
+        }
+
+        Ok(imported)
+    }
+
+    /// Write the subscriptions as an OPML 2.0 document.
+    pub fn to_opml(&self) -> String {
+        let mut opml = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <opml version=\"2.0\">\n  \
+             <head>\n    <title>dumptruckrss subscriptions</title>\n  </head>\n  \
+             <body>\n",
+        );
+
+        for subscription in &self.subscriptions {
+            let title = escape_attribute(&subscription.title);
+            opml.push_str(&format!(
+                "    <outline type=\"rss\" text=\"{}\" title=\"{}\" xmlUrl=\"{}\" {}=\"{}\" {}=\"{}\"/>\n",
+                title,
+                title,
+                escape_attribute(&subscription.url),
+                String::from_utf8_lossy(OUTPUT_ATTRIBUTE),
+                escape_attribute(&subscription.output.to_string_lossy()),
+                String::from_utf8_lossy(QUERY_ATTRIBUTE),
+                escape_attribute(&subscription.query),
+            ));
+        }
+
+        opml.push_str("  </body>\n</opml>\n");
+        opml
+    }
+}
+
+fn escape_attribute(value: &str) -> String {
+    String::from_utf8_lossy(&escape(value.as_bytes())).into_owned()
+}
+
+/// Build a subscription out of an outline element. Outlines without a feed URL are only
+/// used to group feeds and are skipped.
+fn read_outline(
+    reader: &Reader<&[u8]>,
+    element: &BytesStart,
+    base_output: &Path,
+    query: &str,
+) -> Result<Option<Subscription>, Box<RssDumpError>> {
+    let mut url = None;
+    let mut title = None;
+    let mut text = None;
+    let mut output = None;
+    let mut outline_query = None;
+
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        let value = attribute.unescape_and_decode_value(reader)?;
+
+        match attribute.key {
+            b"xmlUrl" => url = Some(value),
+            b"title" => title = Some(value),
+            b"text" => text = Some(value),
+            OUTPUT_ATTRIBUTE => output = Some(PathBuf::from(value)),
+            QUERY_ATTRIBUTE => outline_query = Some(value),
+            _ => (),
+        }
+    }
+
+    let url = match url {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+    let title = title.or(text).unwrap_or_else(|| url.clone());
+
+    Ok(Some(Subscription {
+        // Replace '/' and names like ".." so the title can't escape the base directory
+        output: output.unwrap_or_else(|| {
+            base_output
+                .join(SanitizePolicy::Posix.sanitize_path(Path::new(&title.replace('/', "-")), ""))
+        }),
+        query: outline_query.unwrap_or_else(|| query.to_owned()),
+        interval: None,
+        title,
+        url,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<opml version="1.0">
+  <head><title>Podcasts</title></head>
+  <body>
+    <outline text="Comedy">
+      <outline type="rss" text="Cheese &amp; Wine" xmlUrl="http://example.com/cheese.xml"/>
+      <outline type="rss" title="AC/DC" text="ignored" xmlUrl="http://example.com/acdc.xml"></outline>
+    </outline>
+  </body>
+</opml>"#;
+
+    #[test]
+    fn opml_round_trip() {
+        let mut subscriptions = Subscriptions::default();
+        let imported = subscriptions
+            .import_opml(OPML.as_bytes(), Path::new("/podcasts"), "new")
+            .unwrap();
+        assert_eq!(imported, 2);
+
+        let imported: Vec<&Subscription> = subscriptions.iter().collect();
+        assert_eq!(
+            imported[0],
+            &Subscription {
+                title: "Cheese & Wine".to_owned(),
+                url: "http://example.com/cheese.xml".to_owned(),
+                output: PathBuf::from("/podcasts/Cheese & Wine"),
+                query: "new".to_owned(),
+                interval: None,
+            }
+        );
+        assert_eq!(imported[1].title, "AC/DC");
+        assert_eq!(imported[1].output, PathBuf::from("/podcasts/AC-DC"));
+
+        // Titles can't name the base directory or its parent
+        let mut escaping = Subscriptions::default();
+        escaping
+            .import_opml(
+                OPML.replace("AC/DC", "..")
+                    .replace("Cheese &amp; Wine", ".")
+                    .as_bytes(),
+                Path::new("/podcasts"),
+                "new",
+            )
+            .unwrap();
+        let outputs: Vec<&Path> = escaping.iter().map(|s| s.output.as_path()).collect();
+        assert_eq!(
+            outputs,
+            [Path::new("/podcasts/_"), Path::new("/podcasts/_")]
+        );
+
+        // Settings survive an export, even when importing with other defaults
+        let mut reimported = Subscriptions::default();
+        reimported
+            .import_opml(
+                subscriptions.to_opml().as_bytes(),
+                Path::new("/elsewhere"),
+                "latest",
+            )
+            .unwrap();
+        assert_eq!(
+            reimported.iter().collect::<Vec<&Subscription>>(),
+            subscriptions.iter().collect::<Vec<&Subscription>>()
+        );
+    }
+}
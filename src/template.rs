@@ -0,0 +1,297 @@
+use super::error::RssDumpError;
+use super::model::Item;
+
+use chrono::format::{Item as FormatItem, StrftimeItems};
+
+use std::path::{Component, Path, PathBuf};
+
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Field {
+    Title,
+    Guid,
+    PubDate,
+    Number,
+    Season,
+    Episode,
+    EpisodeType,
+    Explicit,
+    Author,
+    FeedTitle,
+    Ext,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "title" => Some(Field::Title),
+            "guid" => Some(Field::Guid),
+            "pub_date" | "date" => Some(Field::PubDate),
+            "number" | "index" => Some(Field::Number),
+            "season" => Some(Field::Season),
+            "episode" => Some(Field::Episode),
+            "episode_type" => Some(Field::EpisodeType),
+            "explicit" => Some(Field::Explicit),
+            "author" => Some(Field::Author),
+            "feed_title" => Some(Field::FeedTitle),
+            "ext" => Some(Field::Ext),
+            _ => None,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(self, Field::Number | Field::Season | Field::Episode)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Part {
+    Literal(String),
+    Field {
+        field: Field,
+        format: Option<String>,
+    },
+}
+
+/// Template for the path of a downloaded enclosure, relative to the output directory, e.g.
+/// `{feed_title}/{pub_date:%Y-%m-%d} - {number:04} - {title}.{ext}`.
+///
+/// Fields are written as `{name}` or `{name:format}`. Dates take a strftime format and
+/// numbers a width, zero padded when it starts with `0`. Use `{{` and `}}` for literal
+/// braces. A `/` in the template starts a subdirectory, while a `/` in a field's value is
+/// replaced with `-`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FilenameTemplate {
+    parts: Vec<Part>,
+}
+
+impl FilenameTemplate {
+    pub fn parse(template: &str) -> Result<Self, Box<RssDumpError>> {
+        let invalid = |reason: &str| {
+            Box::new(RssDumpError::InvalidTemplate(format!(
+                "{} ({})",
+                template, reason
+            )))
+        };
+
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or_else(|| invalid("unclosed '{'"))?;
+                    let (name, format) = match rest[..end].find(':') {
+                        Some(i) => (&rest[..i], Some(rest[i + 1..end].to_owned())),
+                        None => (&rest[..end], None),
+                    };
+
+                    let field = Field::from_name(name.trim())
+                        .ok_or_else(|| invalid(&format!("unknown field '{}'", name)))?;
+
+                    match (&format, field) {
+                        (None, _) => (),
+                        (Some(format), Field::PubDate) => {
+                            if StrftimeItems::new(format).any(|item| item == FormatItem::Error) {
+                                return Err(invalid(&format!("invalid date format '{}'", format)));
+                            }
+                        }
+                        (Some(format), field) if field.is_numeric() => {
+                            if format.parse::<usize>().is_err() {
+                                return Err(invalid(&format!("invalid width '{}'", format)));
+                            }
+                        }
+                        (Some(_), _) => {
+                            return Err(invalid(&format!("'{}' takes no format", name)));
+                        }
+                    }
+
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field { field, format });
+
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(invalid("unmatched '}'")),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// Path of `item`, the item number `number` of the feed `feed_title`, relative to the
+    /// output directory. Its enclosure is stored with `extension`. Fields missing from the
+    /// item are left empty.
+    pub fn render(&self, item: &Item, number: usize, feed_title: &str, extension: &str) -> PathBuf {
+        let mut rendered = String::new();
+
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Field { field, format } => {
+                    let value = Self::render_field(
+                        *field,
+                        format.as_deref(),
+                        item,
+                        number,
+                        feed_title,
+                        extension,
+                    );
+                    // Values can't create directories of their own
+                    rendered.push_str(&value.replace("/", "-"));
+                }
+            }
+        }
+
+        // Only keep plain components, so the path never leaves the output directory
+        Path::new(&rendered)
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(component) => Some(component),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn render_field(
+        field: Field,
+        format: Option<&str>,
+        item: &Item,
+        number: usize,
+        feed_title: &str,
+        extension: &str,
+    ) -> String {
+        let pad = |n: Option<u64>| match (n, format) {
+            (Some(n), Some(width)) if width.starts_with('0') => {
+                format!("{:0width$}", n, width = width.parse().unwrap_or(0))
+            }
+            (Some(n), Some(width)) => format!("{:width$}", n, width = width.parse().unwrap_or(0)),
+            (Some(n), None) => n.to_string(),
+            (None, _) => String::new(),
+        };
+
+        match field {
+            Field::Title => item.title().unwrap_or_default().to_owned(),
+            Field::Guid => item.guid().unwrap_or_default().to_owned(),
+            Field::PubDate => item.pub_date().map_or_else(String::new, |date| {
+                date.format(format.unwrap_or(DEFAULT_DATE_FORMAT))
+                    .to_string()
+            }),
+            Field::Number => pad(Some(number as u64)),
+            Field::Season => pad(item.season()),
+            Field::Episode => pad(item.episode()),
+            Field::EpisodeType => item.episode_type().to_owned(),
+            Field::Explicit => match item.explicit() {
+                Some(true) => String::from("explicit"),
+                Some(false) => String::from("clean"),
+                None => String::new(),
+            },
+            Field::Author => item.author().unwrap_or_default().to_owned(),
+            Field::FeedTitle => feed_title.to_owned(),
+            Field::Ext => extension.to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Channel;
+
+    const RSS_FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">
+<channel>
+  <title>Cheese Talk</title>
+  <link>http://example.com/</link>
+  <description>Cheese</description>
+  <item>
+    <title>Brie/Camembert: a comparison</title>
+    <guid>urn:episode:7</guid>
+    <author>Someone</author>
+    <pubDate>Mon, 01 Feb 2021 10:00:00 +0000</pubDate>
+    <enclosure url="http://example.com/7.mp3" length="1234" type="audio/mpeg"/>
+    <itunes:season>2</itunes:season>
+    <itunes:episode>7</itunes:episode>
+  </item>
+</channel>
+</rss>"#;
+
+    #[test]
+    fn render_template() {
+        let channel = Channel::read_from(RSS_FEED.as_bytes()).unwrap();
+        let item = &channel.items()[0];
+
+        let template =
+            FilenameTemplate::parse("{pub_date:%Y-%m-%d} - {number:04} - {title}.{ext}").unwrap();
+        assert_eq!(
+            template.render(item, 3, channel.title(), "mp3"),
+            PathBuf::from("2021-02-01 - 0003 - Brie-Camembert: a comparison.mp3")
+        );
+
+        let template = FilenameTemplate::parse(
+            "/{feed_title}/S{season:02}E{episode:03} {{{author}}}/../{guid}",
+        )
+        .unwrap();
+        assert_eq!(
+            template.render(item, 0, channel.title(), "mp3"),
+            PathBuf::from("Cheese Talk/S02E007 {Someone}/urn:episode:7")
+        );
+    }
+
+    #[test]
+    fn render_namespace_fields() {
+        let channel = Channel::read_from(
+            br#"<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd"
+                xmlns:podcast="https://podcastindex.org/namespace/1.0">
+            <channel><title>T</title><link>l</link><description>d</description>
+            <item>
+                <title>Teaser</title>
+                <itunes:episodeType>Trailer</itunes:episodeType>
+                <itunes:explicit>yes</itunes:explicit>
+                <podcast:season>3</podcast:season>
+                <podcast:episode>12</podcast:episode>
+            </item>
+            <item><title>Plain</title></item>
+            </channel></rss>"#,
+        )
+        .unwrap();
+
+        let template =
+            FilenameTemplate::parse("S{season:02}E{episode:02} {episode_type} {explicit}").unwrap();
+        assert_eq!(
+            template.render(&channel.items()[0], 0, channel.title(), "mp3"),
+            PathBuf::from("S03E12 trailer explicit")
+        );
+        assert_eq!(
+            template.render(&channel.items()[1], 1, channel.title(), "mp3"),
+            PathBuf::from("SE full ")
+        );
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!(FilenameTemplate::parse("{title").is_err());
+        assert!(FilenameTemplate::parse("title}").is_err());
+        assert!(FilenameTemplate::parse("{name}").is_err());
+        assert!(FilenameTemplate::parse("{title:04}").is_err());
+        assert!(FilenameTemplate::parse("{number:x}").is_err());
+        assert!(FilenameTemplate::parse("{pub_date:%Q}").is_err());
+    }
+}
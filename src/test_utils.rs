@@ -0,0 +1,39 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// A directory of its own for a test, removed with everything in it when dropped, even when
+/// the test panics.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create a new empty directory for the test called `name`. The directory is created
+    /// exclusively, so tests running at the same time, in this or other processes, never share
+    /// one.
+    pub fn new(name: &str) -> Self {
+        let base = std::env::temp_dir();
+        let mut attempt = 0_u32;
+        loop {
+            let path = base.join(format!(
+                "dumptruckrss-{}-{}-{}",
+                name,
+                std::process::id(),
+                attempt
+            ));
+            match std::fs::create_dir(&path) {
+                Ok(()) => return Self(path),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => attempt += 1,
+                Err(e) => panic!("Failed to create {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,10 +1,51 @@
 use super::error::RssDumpError;
-use super::ext;
 
+use futures::stream::{self, Stream, StreamExt};
+use sha2::{Digest, Sha256};
 use tokio::fs;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
 
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Extension appended to files that are still being downloaded.
+pub const PART_EXTENSION: &str = "part";
+/// Extension appended to the partial file of a segmented download to track its progress.
+pub const SEGMENTS_EXTENSION: &str = "segments";
+
+/// Directory holding the configuration and subscriptions: `$XDG_CONFIG_HOME/dumptruckrss`,
+/// falling back to `~/.config/dumptruckrss`.
+pub fn config_dir() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+/// Directory holding cached feeds: `$XDG_CACHE_HOME/dumptruckrss`, falling back to
+/// `~/.cache/dumptruckrss`.
+pub fn cache_dir() -> PathBuf {
+    xdg_dir("XDG_CACHE_HOME", ".cache")
+}
+
+fn xdg_dir(variable: &str, home_fallback: &str) -> PathBuf {
+    std::env::var_os(variable)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(home_fallback)))
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("dumptruckrss")
+}
+
+/// Replace a leading `~` in `path` with the home directory.
+pub fn expand_home(path: &str) -> String {
+    match (path.strip_prefix('~'), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with('/') => home + rest,
+        _ => path.to_owned(),
+    }
+}
 
 pub fn does_dir_exist(file: &Path) -> bool {
     if file.exists() {
@@ -40,8 +81,7 @@ pub fn is_path_writable(path: &Path) -> Result<bool, Box<RssDumpError>> {
     Ok(((mode >> 7) & 0x1) == 1)
 }
 
-pub fn create_file_path(file: &Path, mime_type: &str, title: &str) -> PathBuf {
-    let extension = ext::AudioType::get_extension_from_mime(mime_type);
+pub fn create_file_path(file: &Path, extension: &str, title: &str) -> PathBuf {
     let mut new_file = PathBuf::from(file);
     new_file.push(
         title
@@ -52,3 +92,203 @@ pub fn create_file_path(file: &Path, mime_type: &str, title: &str) -> PathBuf {
     new_file.set_extension(extension);
     new_file
 }
+
+pub fn create_part_file_path(file: &Path) -> PathBuf {
+    let mut part_file = file.as_os_str().to_owned();
+    part_file.push(".");
+    part_file.push(PART_EXTENSION);
+    PathBuf::from(part_file)
+}
+
+/// File next to the partial file `part_file` of a segmented download, holding how much of
+/// it was downloaded without gaps.
+pub fn create_segments_file_path(part_file: &Path) -> PathBuf {
+    let mut segments_file = part_file.as_os_str().to_owned();
+    segments_file.push(".");
+    segments_file.push(SEGMENTS_EXTENSION);
+    PathBuf::from(segments_file)
+}
+
+/// Compute the SHA-256 of a file, returned as a lowercase hex string.
+pub async fn hash_file(path: &Path) -> Result<String, Box<RssDumpError>> {
+    let path = path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+}
+
+/// Parse a number of bytes, like "500K", "2MB" or "1.5GiB". Suffixes are powers of 1024.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let trimmed = size.trim();
+    // The unit is optional: "2M", "2MB" and "2MiB" are the same size
+    let trimmed = match trimmed.strip_suffix(|c| c == 'B' || c == 'b') {
+        Some(prefixed) if prefixed.ends_with(|c: char| c.is_ascii_alphabetic()) => {
+            prefixed.strip_suffix('i').unwrap_or(prefixed).trim_end()
+        }
+        Some(bytes) => bytes,
+        None => trimmed,
+    };
+    let (number, multiplier) = match trimmed.char_indices().last() {
+        Some((i, suffix)) if suffix.is_ascii_alphabetic() => {
+            let multiplier: u64 = match suffix.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => return None,
+            };
+            (&trimmed[..i], multiplier)
+        }
+        _ => (trimmed, 1),
+    };
+
+    let number: f64 = number.trim().parse().ok()?;
+    let size = (number * multiplier as f64).round();
+    // u64::MAX rounds up to 2^64 as a float, the first size that doesn't fit
+    if size >= 0.0 && size < u64::MAX as f64 {
+        Some(size as u64)
+    } else {
+        None
+    }
+}
+
+/// Parse a duration in whole seconds, given as seconds ("5400"), as "MM:SS" or "HH:MM:SS",
+/// the forms used by `itunes:duration`, or in human units like "1h 30m".
+pub fn parse_duration(duration: &str) -> Option<u64> {
+    let trimmed = duration.trim();
+
+    if trimmed.contains(':') {
+        let fields = trimmed
+            .split(':')
+            .map(|field| field.trim().parse::<u64>().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        if fields.len() > 3 {
+            return None;
+        }
+        return fields.iter().try_fold(0_u64, |seconds, field| {
+            seconds.checked_mul(60)?.checked_add(*field)
+        });
+    }
+
+    match trimmed.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => Some(seconds.round() as u64),
+        Ok(_) => None,
+        Err(_) => humantime::parse_duration(trimmed)
+            .ok()
+            .map(|duration| duration.as_secs()),
+    }
+}
+
+/// Lowercase host name of `url`, if it has one.
+pub fn url_host(url: &str) -> Option<String> {
+    reqwest::Url::parse(url)
+        .ok()?
+        .host_str()
+        .map(str::to_lowercase)
+}
+
+/// HTTP client shared by every request of a run, so connections to the same host are reused.
+pub fn http_client() -> Result<reqwest::Client, Box<RssDumpError>> {
+    Ok(reqwest::Client::builder()
+        .user_agent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .connect_timeout(Duration::from_secs(30))
+        .tcp_keepalive(Duration::from_secs(60))
+        .build()?)
+}
+
+/// Random duration between zero and `max`, used to spread polls and retries.
+pub fn random_duration(max: Duration) -> Duration {
+    let max_millis = max.as_millis() as u64;
+    if max_millis == 0 {
+        return Duration::from_millis(0);
+    }
+
+    // Every RandomState is seeded differently, which is all the randomness we need
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_millis(random % (max_millis + 1))
+}
+
+/// Token cancelled when the process receives SIGTERM or SIGINT, so work can stop cleanly. A
+/// second signal exits right away.
+pub fn cancel_on_signal() -> Result<CancellationToken, Box<RssDumpError>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    let signals = stream::select(
+        stream::poll_fn(move |cx| terminate.poll_recv(cx)),
+        stream::poll_fn(move |cx| interrupt.poll_recv(cx)),
+    );
+
+    Ok(cancel_on(signals, || std::process::exit(130)))
+}
+
+/// Token cancelled on the first of `signals`. The second one calls `quit`.
+fn cancel_on<S, F>(mut signals: S, quit: F) -> CancellationToken
+where
+    S: Stream<Item = ()> + Unpin + Send + 'static,
+    F: FnOnce() + Send + 'static,
+{
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+
+    tokio::spawn(async move {
+        if signals.next().await.is_none() {
+            return;
+        }
+        println!("Stopping. Interrupt again to quit right away");
+        token.cancel();
+
+        if signals.next().await.is_some() {
+            quit();
+        }
+    });
+
+    cancel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::{mpsc, oneshot};
+
+    #[tokio::test]
+    async fn cancel_on_signals() {
+        let (signals, receiver) = mpsc::unbounded();
+        let (quit, mut quitted) = oneshot::channel();
+        let cancel = cancel_on(receiver, move || quit.send(()).unwrap());
+        assert!(!cancel.is_cancelled());
+
+        // The first signal cancels the token
+        signals.unbounded_send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), cancel.cancelled())
+            .await
+            .unwrap();
+        assert_eq!(quitted.try_recv(), Ok(None));
+
+        // And the second quits
+        signals.unbounded_send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), quitted)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
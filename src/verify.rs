@@ -0,0 +1,141 @@
+use super::error::RssDumpError;
+use super::feed::Feed;
+use super::state::{item_key, ItemRecord};
+use super::utils::hash_file;
+
+use tokio::fs;
+use tokio::io as tokio_io;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// What is wrong with a file in the output directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// The file was downloaded but is no longer there.
+    Missing,
+    /// The file is smaller than the download or the enclosure.
+    Truncated { expected: u64, actual: u64 },
+    /// The file's size or contents changed since it was downloaded.
+    Modified,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "missing"),
+            Problem::Truncated { expected, actual } => {
+                write!(f, "truncated, {} of {} bytes", actual, expected)
+            }
+            Problem::Modified => write!(f, "modified since it was downloaded"),
+        }
+    }
+}
+
+/// Check every file in the output directory of `feed` against the size and SHA-256 recorded
+/// when it was downloaded. Files of items that were never recorded are checked against the
+/// length of their enclosure in the feed, when it has one.
+pub async fn verify_feed(feed: &Feed<'_>) -> Result<Vec<(PathBuf, Problem)>, Box<RssDumpError>> {
+    let records = feed.download_records();
+    let output_dir = feed.get_config_output();
+    let mut problems = vec![];
+
+    for record in &records {
+        let path = output_dir.join(&record.path);
+        if let Some(problem) = verify_record(&path, record).await? {
+            problems.push((path, problem));
+        }
+    }
+
+    let recorded: HashSet<&str> = records.iter().map(|record| record.guid.as_str()).collect();
+
+    for item in feed.items() {
+        let expected = match item.enclosure().and_then(|enclosure| enclosure.length()) {
+            Some(expected) if !item_key(item).map_or(false, |key| recorded.contains(key)) => {
+                expected
+            }
+            _ => continue,
+        };
+
+        let path = feed.file_path(item);
+        if let Some(actual) = file_size(&path).await? {
+            if actual < expected {
+                problems.push((path, Problem::Truncated { expected, actual }));
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+async fn verify_record(
+    path: &Path,
+    record: &ItemRecord,
+) -> Result<Option<Problem>, Box<RssDumpError>> {
+    let problem = match file_size(path).await? {
+        None => Some(Problem::Missing),
+        Some(actual) if actual < record.size => Some(Problem::Truncated {
+            expected: record.size,
+            actual,
+        }),
+        Some(actual) if actual > record.size => Some(Problem::Modified),
+        Some(_) if hash_file(path).await? != record.sha256 => Some(Problem::Modified),
+        Some(_) => None,
+    };
+
+    Ok(problem)
+}
+
+/// Size of the file in `path`, or `None` if there is no such file.
+async fn file_size(path: &Path) -> Result<Option<u64>, Box<RssDumpError>> {
+    match fs::metadata(path).await {
+        Ok(metadata) => Ok(Some(metadata.len())),
+        Err(e) if e.kind() == tokio_io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[tokio::test]
+    async fn verify_records() {
+        let dir = TempDir::new("verify");
+        let path = dir.path().join("cheese.mp3");
+        fs::write(&path, b"cheese").await.unwrap();
+
+        let mut record = ItemRecord {
+            guid: String::from("urn:cheese"),
+            url: String::from("http://example.com/cheese.mp3"),
+            path: path.clone(),
+            size: 6,
+            sha256: hash_file(&path).await.unwrap(),
+            downloaded_at: chrono::Utc::now(),
+        };
+        assert_eq!(verify_record(&path, &record).await.unwrap(), None);
+
+        fs::write(&path, b"brie!!").await.unwrap();
+        assert_eq!(
+            verify_record(&path, &record).await.unwrap(),
+            Some(Problem::Modified)
+        );
+
+        record.size = 10;
+        assert_eq!(
+            verify_record(&path, &record).await.unwrap(),
+            Some(Problem::Truncated {
+                expected: 10,
+                actual: 6
+            })
+        );
+
+        fs::remove_file(&path).await.unwrap();
+        assert_eq!(
+            verify_record(&path, &record).await.unwrap(),
+            Some(Problem::Missing)
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use super::config::DumpConfig;
+use super::error::RssDumpError;
+use super::feed::{fetch_channel, Feed};
+use super::query::expr::QueryExpr;
+use super::query::QueryOp;
+use super::space::check_free_space;
+use super::utils::random_duration;
+
+use chrono::{Local, NaiveDate};
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
+
+use std::time::Duration;
+
+/// A feed polled by `watch`, together with the query run against it on every poll.
+pub struct WatchedFeed<'a> {
+    config: &'a DumpConfig<'a>,
+    is_url: bool,
+    query: &'a str,
+    // The query compiled on `query_day`, compiled again once relative dates move on
+    query_op: QueryOp<'a>,
+    query_day: NaiveDate,
+    interval: Duration,
+    next_poll: Instant,
+}
+
+impl<'a> WatchedFeed<'a> {
+    /// The first poll happens right away. Fails when `query` is not a valid query.
+    pub fn new(
+        config: &'a DumpConfig<'a>,
+        is_url: bool,
+        query: &'a str,
+        interval: Duration,
+    ) -> Result<Self, Box<RssDumpError>> {
+        let today = Local::today().naive_local();
+
+        Ok(Self {
+            config,
+            is_url,
+            query,
+            query_op: QueryExpr::new_at(query, today)?.build_query_op(),
+            query_day: today,
+            interval,
+            next_poll: Instant::now(),
+        })
+    }
+
+    /// Fetch the feed and download the items matching the query that were not downloaded yet.
+    async fn poll(&mut self, cancel: &CancellationToken) -> Result<(), Box<RssDumpError>> {
+        let fetched = tokio::select! {
+            fetched = fetch_channel(
+                self.config.get_feed(),
+                self.is_url,
+                self.config.get_client(),
+                self.config.get_headers(),
+                self.config.get_retry_policy(),
+            ) => fetched?,
+            _ = cancel.cancelled() => return Ok(()),
+        };
+
+        let mut feed = Feed::new(fetched.channel, self.config).await?;
+        if feed.unchanged_since_last_run(fetched.not_modified_since) {
+            info!("{} was not modified", self.config.get_feed());
+            return Ok(());
+        }
+
+        let today = Local::today().naive_local();
+        if today != self.query_day {
+            self.query_op = QueryExpr::new_at(self.query, today)?.build_query_op();
+            self.query_day = today;
+        }
+        let query_op = &self.query_op;
+        let new_items: QueryOp =
+            Box::new(move |(item, i, feed)| !feed.is_downloaded(item) && query_op((item, i, feed)));
+        let mut download_list = feed.build_download_list(&new_items)?;
+        check_free_space(&[&feed], std::slice::from_mut(&mut download_list)).await?;
+
+        if download_list.is_empty() {
+            info!("No new items in {}", feed.title());
+            return feed.record_run();
+        }
+
+        println!(
+            "Downloading {} new items from {}",
+            download_list.len(),
+            feed.title()
+        );
+
+        // Failed downloads are not in the download state, so the next poll retries them
+        let failed_downs = feed.download_items(&download_list, cancel).await;
+        if !failed_downs.is_empty() {
+            println!(
+                "{} Downloads from {} failed. Retrying on the next poll",
+                failed_downs.len(),
+                feed.title()
+            );
+            Ok(())
+        } else {
+            feed.record_run()
+        }
+    }
+
+    /// Schedule the next poll one interval after `now`, delayed by up to `max_jitter`.
+    fn schedule_next_poll(&mut self, now: Instant, max_jitter: Duration) {
+        self.next_poll = now + self.interval + random_duration(max_jitter);
+    }
+}
+
+/// The feed whose poll is due first, if there is any.
+fn next_due<'f, 'a>(feeds: &'f mut [WatchedFeed<'a>]) -> Option<&'f mut WatchedFeed<'a>> {
+    feeds.iter_mut().min_by_key(|feed| feed.next_poll)
+}
+
+/// Poll every feed on its own interval, delayed by up to `max_jitter`, until `cancel` is
+/// cancelled. A poll interrupted by it leaves its downloads in their `.part` files, so they
+/// resume on the next run.
+pub async fn watch(
+    feeds: &mut [WatchedFeed<'_>],
+    max_jitter: Duration,
+    cancel: &CancellationToken,
+) -> Result<(), Box<RssDumpError>> {
+    loop {
+        let next = match next_due(feeds) {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+
+        tokio::select! {
+            _ = sleep_until(next.next_poll) => (),
+            _ = cancel.cancelled() => break,
+        }
+
+        if let Err(e) = next.poll(cancel).await {
+            println!(
+                "Failed to poll {}. Retrying on the next poll",
+                next.config.get_feed()
+            );
+            warn!("{}", e);
+        }
+        if cancel.is_cancelled() {
+            break;
+        }
+
+        next.schedule_next_poll(Instant::now(), max_jitter);
+        info!(
+            "Polling {} again in {}s",
+            next.config.get_feed(),
+            (next.next_poll - Instant::now()).as_secs()
+        );
+    }
+
+    println!("Stopped watching");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedule_polls() {
+        let config = DumpConfig::new_output_is_dir(".", 1, "feed", 0, reqwest::Client::new());
+        let (hourly, daily) = (Duration::from_secs(3600), Duration::from_secs(86400));
+        let mut feeds = [
+            WatchedFeed::new(&config, true, "new", daily).unwrap(),
+            WatchedFeed::new(&config, true, "new", hourly).unwrap(),
+        ];
+        let now = Instant::now();
+
+        // Without jitter polls are exactly one interval apart
+        feeds[0].schedule_next_poll(now, Duration::ZERO);
+        assert_eq!(feeds[0].next_poll, now + daily);
+
+        // With it they are delayed by up to the jitter, never less than the interval
+        let jitter = Duration::from_secs(300);
+        for _ in 0..100 {
+            feeds[1].schedule_next_poll(now, jitter);
+            assert!(feeds[1].next_poll >= now + hourly);
+            assert!(feeds[1].next_poll <= now + hourly + jitter);
+        }
+
+        // The feed due first is polled first
+        assert_eq!(next_due(&mut feeds).unwrap().interval, hourly);
+        feeds[1].schedule_next_poll(now + daily, jitter);
+        assert_eq!(next_due(&mut feeds).unwrap().interval, daily);
+        assert!(next_due(&mut []).is_none());
+    }
+
+    #[test]
+    fn reject_invalid_queries() {
+        let config = DumpConfig::new_output_is_dir(".", 1, "feed", 0, reqwest::Client::new());
+        let hourly = Duration::from_secs(3600);
+
+        assert!(matches!(
+            *WatchedFeed::new(&config, true, "number:[1", hourly)
+                .err()
+                .unwrap(),
+            RssDumpError::Query(_)
+        ));
+        assert!(WatchedFeed::new(&config, true, "date:last-7d", hourly).is_ok());
+    }
+}